@@ -0,0 +1,51 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tinylfu::sketch::{FrequencySketch, ShardedFrequencySketch};
+
+const SKETCH_SIZE: usize = 1 << 17;
+const THREADS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+
+fn run_threads(threads: usize, iters: u64, increment: impl Fn(u64) + Sync) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for t in 0..threads as u64 {
+            let increment = &increment;
+            s.spawn(move || {
+                let offset = t << 32;
+                for i in 0..iters {
+                    increment(offset + i);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("FrequencySketch::contended_increment");
+    for threads in THREADS {
+        group.throughput(Throughput::Elements(threads as u64));
+        let sketch = Mutex::new(FrequencySketch::with_capacity(SKETCH_SIZE));
+        group.bench_function(BenchmarkId::new("Mutex", threads), |b| {
+            b.iter_custom(|iters| {
+                run_threads(threads, iters, |key| {
+                    black_box(sketch.lock().unwrap().increment(&key));
+                })
+            })
+        });
+        let sketch = ShardedFrequencySketch::with_capacity(SKETCH_SIZE, threads.max(2) * 4);
+        group.bench_function(BenchmarkId::new("Sharded", threads), |b| {
+            b.iter_custom(|iters| {
+                run_threads(threads, iters, |key| {
+                    black_box(sketch.increment(&key));
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_contention);
+criterion_main!(benches);
@@ -2,15 +2,12 @@ use ahash::RandomState;
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
 use moka_sketch::MokaSketch;
 use std::hash::{BuildHasher, Hash};
-use tinylfu::sketch::FrequencySketch;
+use tinylfu::sketch::{Backend, FrequencySketch};
 
 mod moka_sketch;
 
 fn make_hash<Q: Hash + ?Sized, S: BuildHasher>(hash_builder: &S, val: &Q) -> u64 {
-    use core::hash::Hasher;
-    let mut state = hash_builder.build_hasher();
-    val.hash(&mut state);
-    state.finish()
+    hash_builder.hash_one(val)
 }
 
 fn bench_sketch(c: &mut Criterion) {
@@ -29,7 +26,7 @@ fn bench_sketch(c: &mut Criterion) {
     let mut group = c.benchmark_group("MokaSketch::frequency");
     for size in SIZES {
         let state = RandomState::new();
-        let mut sketch = MokaSketch::with_capacity((size as usize) * 8);
+        let mut sketch = MokaSketch::with_capacity(size * 8);
         for i in 0..size {
             sketch.increment(make_hash(&state, &i));
         }
@@ -69,9 +66,31 @@ fn bench_sketch(c: &mut Criterion) {
         });
     }
     group.finish();
+    let mut group = c.benchmark_group("FrequencySketch::frequency_many");
+    for size in SIZES {
+        let mut sketch = FrequencySketch::with_capacity(size);
+        for i in 0..size {
+            sketch.increment(&i);
+        }
+        let sketch = sketch;
+        let mut counter: usize = 0;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let mut keys = [0; 8];
+                let mut frequencies = [0; 8];
+                for key in keys.iter_mut() {
+                    counter += 1;
+                    *key = counter;
+                }
+                black_box(&sketch).frequency_many(&keys, &mut frequencies);
+                frequencies.iter().map(|&freq| freq as u32).sum::<u32>()
+            })
+        });
+    }
+    group.finish();
     let mut group = c.benchmark_group("MokaSketch::increment");
     for size in SIZES {
-        let mut sketch = MokaSketch::with_capacity((size as usize) * 8);
+        let mut sketch = MokaSketch::with_capacity(size * 8);
         let state = RandomState::new();
         let mut counter: usize = 0;
         group.bench_function(BenchmarkId::from_parameter(size), |b| {
@@ -106,11 +125,27 @@ fn bench_sketch(c: &mut Criterion) {
         });
     }
     group.finish();
+    let mut group = c.benchmark_group("FrequencySketch::increment_many");
+    for size in SIZES {
+        let mut sketch = FrequencySketch::with_capacity(size);
+        let mut counter: usize = 0;
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                let mut keys = [0; 8];
+                for key in keys.iter_mut() {
+                    counter += 1;
+                    *key = counter;
+                }
+                black_box(&mut sketch).increment_many(&keys);
+            })
+        });
+    }
+    group.finish();
 
     let mut group = c.benchmark_group("MokaSketch::reset");
     for size in SIZES {
         let state = RandomState::new();
-        let mut sketch = MokaSketch::with_capacity((size as usize) * 8);
+        let mut sketch = MokaSketch::with_capacity(size * 8);
         for i in 0..size {
             sketch.increment(make_hash(&state, &i));
         }
@@ -121,5 +156,48 @@ fn bench_sketch(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_sketch);
+fn bench_backends(c: &mut Criterion) {
+    const SIZE: usize = 1 << 17;
+    let mut group = c.benchmark_group("FrequencySketch::backend");
+    for backend in Backend::ALL {
+        if !backend.is_supported() {
+            continue;
+        }
+        let mut sketch = FrequencySketch::with_capacity(SIZE);
+        sketch.force_backend(backend);
+        for i in 0..SIZE {
+            sketch.increment(&i);
+        }
+        let name = format!("{:?}", backend);
+        group.bench_function(BenchmarkId::new("reset", &name), |b| {
+            b.iter(|| black_box(&mut sketch).reset())
+        });
+        let mut counter: usize = 0;
+        group.bench_function(BenchmarkId::new("frequency", &name), |b| {
+            b.iter(|| {
+                let mut freq = 0;
+                let sketch = black_box(&sketch);
+                for _ in 0..8 {
+                    counter += 1;
+                    freq += sketch.frequency(&counter) as u32;
+                }
+                freq
+            })
+        });
+        group.bench_function(BenchmarkId::new("increment", &name), |b| {
+            b.iter(|| {
+                let mut freq = 0;
+                let sketch = black_box(&mut sketch);
+                for _ in 0..8 {
+                    counter += 1;
+                    freq += sketch.increment(&counter);
+                }
+                freq
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sketch, bench_backends);
 criterion_main!(benches);
@@ -60,7 +60,7 @@ impl MokaSketch {
             maximum.next_power_of_two()
         };
         let table = vec![0; table_size];
-        let table_mask = 0.max(table_size - 1);
+        let table_mask = table_size - 1;
         let sample_size = if cap == 0 {
             10
         } else {
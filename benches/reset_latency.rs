@@ -0,0 +1,46 @@
+use std::time::Instant;
+use tinylfu::sketch::{FrequencySketch, SampleHalving};
+
+const SIZES: [usize; 3] = [1 << 16, 1 << 20, 1 << 23];
+const FACTOR: usize = 16;
+
+fn percentile(sorted: &[u32], p: f64) -> u32 {
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+/// Times every increment over two sample periods and prints the latency distribution, which a
+/// throughput benchmark would average away.
+fn increment_latencies(mut sketch: FrequencySketch) -> Vec<u32> {
+    // Fault in every page up front, so the first touches don't show up as latency spikes.
+    sketch.reset();
+    sketch.finish_reset();
+    let mut latencies = vec![0u32; sketch.sample_size() * 2];
+    for (key, latency) in latencies.iter_mut().enumerate() {
+        let start = Instant::now();
+        sketch.increment(&key);
+        *latency = start.elapsed().as_nanos() as u32;
+    }
+    latencies.sort_unstable();
+    latencies
+}
+
+fn main() {
+    for size in SIZES {
+        for incremental in [false, true] {
+            let builder = FrequencySketch::builder(size).aging(SampleHalving { factor: FACTOR });
+            let sketch = match incremental {
+                true => builder.incremental_reset(1).build(),
+                false => builder.build(),
+            };
+            let latencies = increment_latencies(sketch);
+            println!(
+                "FrequencySketch::increment/{:<20} p50 {:>5}ns  p99 {:>5}ns  p99.99 {:>6}ns  max {:>9}ns",
+                format!("{}/{}", size, if incremental { "incremental" } else { "serial" }),
+                percentile(&latencies, 0.5),
+                percentile(&latencies, 0.99),
+                percentile(&latencies, 0.9999),
+                latencies[latencies.len() - 1],
+            );
+        }
+    }
+}
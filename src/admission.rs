@@ -0,0 +1,123 @@
+//! Admission policies that decide whether a newcomer may replace an eviction victim.
+//!
+//! [`TinyLfuAdmittor`] can be bolted onto any existing eviction policy (LRU, CLOCK, ...): record
+//! every access with [`Admittor::record`], and when the cache is full ask [`Admittor::admit`]
+//! whether the candidate should evict the policy's victim or be dropped itself.
+
+use crate::sketch::{max_lines, FrequencySketch, FrequencySketchBuilder};
+use crate::Global;
+use ahash::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+pub trait Admittor<K: ?Sized> {
+    /// Records an access to `key`.
+    fn record(&mut self, key: &K);
+
+    /// Returns `true` if `candidate` should be admitted in place of `victim`.
+    fn admit(&mut self, candidate: &K, victim: &K) -> bool;
+}
+
+/// Admits every candidate, turning the surrounding cache back into its plain eviction policy.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct AlwaysAdmit;
+
+impl<K: ?Sized> Admittor<K> for AlwaysAdmit {
+    fn record(&mut self, _: &K) {}
+
+    fn admit(&mut self, _: &K, _: &K) -> bool {
+        true
+    }
+}
+
+/// The TinyLFU admission policy: a doorkeeper Bloom filter in front of a [`FrequencySketch`].
+///
+/// The first access to a key only sets its doorkeeper bits, so one-hit wonders never reach the
+/// counters. A candidate is admitted only if its estimate is strictly higher than the victim's;
+/// ties keep the victim, which already proved itself by being cached.
+pub struct TinyLfuAdmittor<S: BuildHasher = RandomState> {
+    sketch: FrequencySketch<S, Global>,
+}
+
+impl TinyLfuAdmittor<RandomState> {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_hasher(capacity, RandomState::new())
+    }
+}
+
+impl<S: BuildHasher> TinyLfuAdmittor<S> {
+    /// Creates an admittor sized for a cache of `capacity` entries.
+    pub fn with_hasher(capacity: usize, hasher: S) -> Self {
+        let sketch_size = capacity.div_ceil(8).clamp(1, max_lines());
+        let sketch = FrequencySketchBuilder::new(sketch_size)
+            .hasher(hasher)
+            .doorkeeper(capacity)
+            .build();
+        Self { sketch }
+    }
+
+    pub fn sketch(&self) -> &FrequencySketch<S, Global> {
+        &self.sketch
+    }
+
+    pub fn frequency<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.frequency_hash(self.hasher().hash_one(key))
+    }
+
+    pub(crate) fn hasher(&self) -> &S {
+        self.sketch.hasher()
+    }
+
+    pub(crate) fn frequency_hash(&self, hash: u64) -> u8 {
+        self.sketch.frequency_hash(hash)
+    }
+
+    pub(crate) fn record_hash(&mut self, hash: u64) {
+        self.sketch.increment_hash(hash);
+    }
+
+    pub(crate) fn admit_hash(&self, candidate: u64, victim: u64) -> bool {
+        self.frequency_hash(candidate) > self.frequency_hash(victim)
+    }
+}
+
+impl<K: Hash + ?Sized, S: BuildHasher> Admittor<K> for TinyLfuAdmittor<S> {
+    fn record(&mut self, key: &K) {
+        self.record_hash(self.hasher().hash_one(key));
+    }
+
+    fn admit(&mut self, candidate: &K, victim: &K) -> bool {
+        let hasher = self.hasher();
+        let (candidate, victim) = (hasher.hash_one(candidate), hasher.hash_one(victim));
+        self.admit_hash(candidate, victim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doorkeeper() {
+        let mut admittor = TinyLfuAdmittor::new(1024);
+        assert!(admittor.sketch().has_doorkeeper());
+        admittor.record(&1);
+        assert_eq!(admittor.frequency(&1), 1);
+        admittor.record(&1);
+        admittor.record(&1);
+        assert_eq!(admittor.frequency(&1), 3);
+    }
+
+    #[test]
+    fn test_admit() {
+        let mut admittor = TinyLfuAdmittor::new(1024);
+        for _ in 0..5 {
+            admittor.record("hot");
+        }
+        admittor.record("cold");
+        assert!(admittor.admit("hot", "cold"));
+        assert!(!admittor.admit("cold", "hot"));
+        assert!(!admittor.admit("cold", "cold"));
+        assert!(!admittor.admit("unseen", "cold"));
+        assert!(Admittor::<str>::admit(&mut AlwaysAdmit, "unseen", "hot"));
+    }
+}
@@ -0,0 +1,401 @@
+//! A bounded W-TinyLFU cache.
+//!
+//! New entries land in a small LRU admission window. Entries evicted from the window compete with
+//! the least recently used entry of the main space, a segmented LRU split into probation and
+//! protected segments, and only the one the [`TinyLfuAdmittor`] considers more popular stays.
+
+use crate::admission::TinyLfuAdmittor;
+use ahash::RandomState;
+use hashbrown::HashTable;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+
+const NIL: usize = usize::MAX;
+const DEFAULT_WINDOW_PERCENT: usize = 1;
+const PROTECTED_PERCENT: usize = 80;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Region {
+    Window,
+    Probation,
+    Protected,
+}
+
+struct Node<K, V> {
+    key: K,
+    value: V,
+    hash: u64,
+    region: Region,
+    prev: usize,
+    next: usize,
+}
+
+#[derive(Copy, Clone)]
+struct Deque {
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl Deque {
+    const EMPTY: Deque = Deque {
+        head: NIL,
+        tail: NIL,
+        len: 0,
+    };
+}
+
+pub struct Cache<K, V, S: BuildHasher = RandomState> {
+    table: HashTable<usize>,
+    nodes: Vec<Node<K, V>>,
+    deques: [Deque; 3],
+    window_capacity: usize,
+    protected_capacity: usize,
+    capacity: usize,
+    admittor: TinyLfuAdmittor<S>,
+}
+
+impl<K: Hash + Eq, V> Cache<K, V, RandomState> {
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_hasher(capacity, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Cache<K, V, S> {
+    /// Creates a cache holding up to `capacity` entries, 1% of which form the admission window.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_hasher(capacity: usize, hasher: S) -> Self {
+        let window_capacity = (capacity * DEFAULT_WINDOW_PERCENT / 100).max(1);
+        Self::with_window_and_hasher(capacity, window_capacity, hasher)
+    }
+
+    /// Creates a cache holding up to `capacity` entries, `window_capacity` of which form the
+    /// admission window. The remaining main space is split 20/80 between probation and protected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window_capacity` is zero or greater than `capacity`.
+    pub fn with_window_and_hasher(capacity: usize, window_capacity: usize, hasher: S) -> Self {
+        assert!(
+            window_capacity > 0 && window_capacity <= capacity,
+            "0 < window <= capacity"
+        );
+        let main_capacity = capacity - window_capacity;
+        Self {
+            table: HashTable::with_capacity(capacity),
+            nodes: Vec::with_capacity(capacity),
+            deques: [Deque::EMPTY; 3],
+            window_capacity,
+            protected_capacity: main_capacity * PROTECTED_PERCENT / 100,
+            capacity,
+            admittor: TinyLfuAdmittor::with_hasher(capacity, hasher),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn window_capacity(&self) -> usize {
+        self.window_capacity
+    }
+
+    pub fn main_capacity(&self) -> usize {
+        self.capacity - self.window_capacity
+    }
+
+    pub fn admittor(&self) -> &TinyLfuAdmittor<S> {
+        &self.admittor
+    }
+
+    /// Returns the value for `key` without recording an access or changing recency.
+    pub fn peek<Q: Hash + Eq + ?Sized>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        let hash = self.admittor.hasher().hash_one(key);
+        self.find(hash, key).map(|idx| &self.nodes[idx].value)
+    }
+
+    /// Returns the value for `key`, recording the access in the sketch and promoting the entry.
+    pub fn get<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        let hash = self.admittor.hasher().hash_one(key);
+        self.admittor.record_hash(hash);
+        let idx = self.find(hash, key)?;
+        self.on_hit(idx);
+        Some(&self.nodes[idx].value)
+    }
+
+    /// Inserts `value` for `key`, returning the previous value if the key was present.
+    ///
+    /// A new entry may be evicted right away if it loses the admission contest against the
+    /// main space's victim.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let hash = self.admittor.hasher().hash_one(&key);
+        self.admittor.record_hash(hash);
+        if let Some(idx) = self.find(hash, &key) {
+            self.on_hit(idx);
+            return Some(mem::replace(&mut self.nodes[idx].value, value));
+        }
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            key,
+            value,
+            hash,
+            region: Region::Window,
+            prev: NIL,
+            next: NIL,
+        });
+        let nodes = &self.nodes;
+        self.table.insert_unique(hash, idx, |&idx| nodes[idx].hash);
+        self.push_back(idx, Region::Window);
+        self.evict();
+        None
+    }
+
+    pub fn remove<Q: Hash + Eq + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let hash = self.admittor.hasher().hash_one(key);
+        let idx = self.find(hash, key)?;
+        Some(self.remove_node(idx).value)
+    }
+
+    fn find<Q: Eq + ?Sized>(&self, hash: u64, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+    {
+        let nodes = &self.nodes;
+        let eq = |&idx: &usize| nodes[idx].key.borrow() == key;
+        self.table.find(hash, eq).copied()
+    }
+
+    fn on_hit(&mut self, idx: usize) {
+        match self.nodes[idx].region {
+            Region::Window => self.move_to_back(idx, Region::Window),
+            Region::Protected => self.move_to_back(idx, Region::Protected),
+            Region::Probation => {
+                self.move_to_back(idx, Region::Protected);
+                if self.deques[Region::Protected as usize].len > self.protected_capacity {
+                    let demoted = self.deques[Region::Protected as usize].head;
+                    self.move_to_back(demoted, Region::Probation);
+                }
+            }
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.deques[Region::Window as usize].len > self.window_capacity {
+            let candidate = self.deques[Region::Window as usize].head;
+            let main_len = self.len() - self.deques[Region::Window as usize].len;
+            if main_len < self.main_capacity() {
+                self.move_to_back(candidate, Region::Probation);
+                continue;
+            }
+            let victim = match self.main_victim() {
+                Some(victim) => victim,
+                None => {
+                    self.remove_node(candidate);
+                    continue;
+                }
+            };
+            let (candidate_hash, victim_hash) =
+                (self.nodes[candidate].hash, self.nodes[victim].hash);
+            if self.admittor.admit_hash(candidate_hash, victim_hash) {
+                self.move_to_back(candidate, Region::Probation);
+                self.remove_node(victim);
+            } else {
+                self.remove_node(candidate);
+            }
+        }
+    }
+
+    fn main_victim(&self) -> Option<usize> {
+        [Region::Probation, Region::Protected]
+            .iter()
+            .map(|&region| self.deques[region as usize].head)
+            .find(|&idx| idx != NIL)
+    }
+
+    fn move_to_back(&mut self, idx: usize, region: Region) {
+        self.unlink(idx);
+        self.push_back(idx, region);
+    }
+
+    fn push_back(&mut self, idx: usize, region: Region) {
+        let deque = &mut self.deques[region as usize];
+        let tail = mem::replace(&mut deque.tail, idx);
+        if tail == NIL {
+            deque.head = idx;
+        } else {
+            self.nodes[tail].next = idx;
+        }
+        deque.len += 1;
+        let node = &mut self.nodes[idx];
+        node.region = region;
+        node.prev = tail;
+        node.next = NIL;
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let Node {
+            prev, next, region, ..
+        } = self.nodes[idx];
+        let deque = &mut self.deques[region as usize];
+        match prev {
+            NIL => deque.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => deque.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+        deque.len -= 1;
+    }
+
+    fn remove_node(&mut self, idx: usize) -> Node<K, V> {
+        self.unlink(idx);
+        let hash = self.nodes[idx].hash;
+        if let Ok(entry) = self.table.find_entry(hash, |&i| i == idx) {
+            entry.remove();
+        }
+        let node = self.nodes.swap_remove(idx);
+        if idx == self.nodes.len() {
+            return node;
+        }
+        let moved = self.nodes.len();
+        let Node {
+            prev,
+            next,
+            region,
+            hash,
+            ..
+        } = self.nodes[idx];
+        let deque = &mut self.deques[region as usize];
+        match prev {
+            NIL => deque.head = idx,
+            prev => self.nodes[prev].next = idx,
+        }
+        match next {
+            NIL => deque.tail = idx,
+            next => self.nodes[next].prev = idx,
+        }
+        if let Some(slot) = self.table.find_mut(hash, |&i| i == moved) {
+            *slot = idx;
+        }
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_consistent<K: Hash + Eq, V, S: BuildHasher>(cache: &Cache<K, V, S>) {
+        assert!(cache.len() <= cache.capacity());
+        assert_eq!(cache.table.len(), cache.len());
+        let mut total = 0;
+        for (region, deque) in cache.deques.iter().enumerate() {
+            let (mut idx, mut prev, mut len) = (deque.head, NIL, 0);
+            while idx != NIL {
+                let node = &cache.nodes[idx];
+                assert_eq!(node.region as usize, region);
+                assert_eq!(node.prev, prev);
+                assert_eq!(cache.find(node.hash, &node.key), Some(idx));
+                prev = idx;
+                idx = node.next;
+                len += 1;
+            }
+            assert_eq!(deque.tail, prev);
+            assert_eq!(deque.len, len);
+            total += len;
+        }
+        assert_eq!(total, cache.len());
+        assert!(cache.deques[Region::Window as usize].len <= cache.window_capacity);
+        assert!(cache.deques[Region::Protected as usize].len <= cache.protected_capacity);
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut cache = Cache::new(100);
+        assert_eq!(cache.insert("a", 1), None);
+        assert_eq!(cache.insert("b", 2), None);
+        assert_eq!(cache.insert("a", 3), Some(1));
+        assert_eq!(cache.get("a"), Some(&3));
+        assert_eq!(cache.peek("b"), Some(&2));
+        assert_eq!(cache.get("c"), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.remove("a"), Some(3));
+        assert_eq!(cache.remove("a"), None);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.len(), 1);
+        assert_consistent(&cache);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut cache = Cache::new(1000);
+        for i in 0..100_000u32 {
+            cache.insert(i % 4096, i);
+            if i % 3 == 0 {
+                cache.get(&(i % 512));
+            }
+            if i % 7 == 0 {
+                cache.remove(&(i % 2048));
+            }
+        }
+        assert!(cache.len() <= 1000);
+        assert_consistent(&cache);
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        let mut cache = Cache::new(1000);
+        for _ in 0..20 {
+            for i in 0..500u32 {
+                cache.insert(i, i);
+            }
+        }
+        for i in 1_000_000..1_100_000u32 {
+            cache.insert(i, i);
+        }
+        let hits = (0..500u32).filter(|i| cache.peek(i).is_some()).count();
+        assert!(hits > 450, "only {} hot keys survived the scan", hits);
+        assert_consistent(&cache);
+    }
+
+    #[test]
+    fn test_tiny_capacity() {
+        let mut cache = Cache::new(1);
+        for i in 0..100u32 {
+            cache.insert(i, i);
+            assert_eq!(cache.len(), 1);
+            assert_consistent(&cache);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "0 < window <= capacity")]
+    fn test_zero_capacity() {
+        Cache::<u32, u32>::new(0);
+    }
+}
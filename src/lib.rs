@@ -1,12 +1,14 @@
-#![cfg_attr(feature = "stdavx512", feature(stdsimd))]
-#![feature(allocator_api)]
-#![feature(new_uninit)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
 #[macro_use]
 extern crate cfg_if;
 
+pub mod admission;
+pub mod cache;
 pub mod sketch;
 
+pub use allocator_api2::alloc::{Allocator, Global};
+
 #[derive(Debug, Copy, Clone)]
 pub struct LkkRemainder(u64, u32);
 
@@ -0,0 +1,225 @@
+use super::{CacheLine, Kernels};
+use std::time::{Duration, Instant};
+
+/// How [`FrequencySketch::reset`](super::FrequencySketch::reset) ages the counters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum Decay {
+    /// Halves every counter, rounding down.
+    #[default]
+    Halve,
+    /// Decrements every non-zero counter by one, which forgets rarely seen keys while keeping the
+    /// relative order of frequent ones.
+    SubtractOne,
+}
+
+impl Decay {
+    /// Ages `cache_line`, returning the number of counters the samples forgotten are derived from.
+    pub(super) fn apply(self, kernels: &Kernels, cache_line: &mut CacheLine) -> u8 {
+        match self {
+            Decay::Halve => kernels.reset(cache_line),
+            Decay::SubtractOne => kernels.decrement(cache_line),
+        }
+    }
+
+    /// Ages an estimate read from a cache line that hasn't been aged yet.
+    pub(super) fn estimate(self, frequency: u8) -> u8 {
+        match self {
+            Decay::Halve => frequency >> 1,
+            Decay::SubtractOne => frequency.saturating_sub(1),
+        }
+    }
+
+    /// Returns the sample count once aging starts, before subtracting [`forgotten`](Self::forgotten).
+    pub(super) fn start_size(self, size: usize) -> usize {
+        match self {
+            Decay::Halve => size >> 1,
+            Decay::SubtractOne => size,
+        }
+    }
+
+    /// Returns the samples forgotten by aging, from the sum of [`apply`](Self::apply) over lines.
+    pub(super) fn forgotten(self, count: usize) -> usize {
+        match self {
+            Decay::Halve => count >> 2,
+            Decay::SubtractOne => count >> 3,
+        }
+    }
+}
+
+/// Decides when and how a [`FrequencySketch`](super::FrequencySketch) ages its counters.
+///
+/// The sketch counts every sample that changed its counters and, once the count reaches
+/// [`sample_size`](Self::sample_size), asks [`should_age`](Self::should_age). If it agrees, the
+/// counters are aged with [`decay`](Self::decay), otherwise the sketch asks again after another
+/// `sample_size` samples.
+///
+/// Policies are `Clone`, so that sketches using them can be cloned.
+pub trait AgingPolicy: Send + Sync + CloneAgingPolicy {
+    /// Returns the number of samples between aging decisions for a sketch of `lines` cache
+    /// lines, or `usize::MAX` to never age on its own.
+    fn sample_size(&self, lines: usize) -> usize;
+
+    /// Returns whether to age the counters now.
+    fn should_age(&mut self) -> bool {
+        true
+    }
+
+    fn decay(&self) -> Decay {
+        Decay::Halve
+    }
+}
+
+/// Clones a boxed [`AgingPolicy`], implemented for every `Clone` policy.
+pub trait CloneAgingPolicy {
+    fn clone_box(&self) -> Box<dyn AgingPolicy>;
+}
+
+impl<P: AgingPolicy + Clone + 'static> CloneAgingPolicy for P {
+    fn clone_box(&self) -> Box<dyn AgingPolicy> {
+        Box::new(self.clone())
+    }
+}
+
+/// Halves the counters every `factor` samples per cache line, 80 by default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SampleHalving {
+    pub factor: usize,
+}
+
+impl Default for SampleHalving {
+    fn default() -> Self {
+        Self { factor: 80 }
+    }
+}
+
+impl AgingPolicy for SampleHalving {
+    fn sample_size(&self, lines: usize) -> usize {
+        lines.saturating_mul(self.factor)
+    }
+}
+
+/// Halves the counters once `interval` has passed since the last halving.
+///
+/// The clock is only read once every as many samples as the sketch has cache lines, so a nearly
+/// idle sketch ages late.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TimeHalving {
+    interval: Duration,
+    last: Instant,
+}
+
+impl TimeHalving {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl AgingPolicy for TimeHalving {
+    fn sample_size(&self, lines: usize) -> usize {
+        lines
+    }
+
+    fn should_age(&mut self) -> bool {
+        let now = Instant::now();
+        let age = now.duration_since(self.last) >= self.interval;
+        if age {
+            self.last = now;
+        }
+        age
+    }
+}
+
+/// Never ages on its own: the counters only halve on an explicit
+/// [`reset`](super::FrequencySketch::reset).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ManualAging;
+
+impl AgingPolicy for ManualAging {
+    fn sample_size(&self, _: usize) -> usize {
+        usize::MAX
+    }
+}
+
+/// Decrements every non-zero counter every `factor` samples per cache line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SubtractOneDecay {
+    pub factor: usize,
+}
+
+impl AgingPolicy for SubtractOneDecay {
+    fn sample_size(&self, lines: usize) -> usize {
+        lines.saturating_mul(self.factor)
+    }
+
+    fn decay(&self) -> Decay {
+        Decay::SubtractOne
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::seeded;
+    use super::super::FrequencySketch;
+    use super::*;
+
+    fn build<P: AgingPolicy + 'static>(policy: P) -> FrequencySketch {
+        seeded(16).aging(policy).build()
+    }
+
+    #[test]
+    fn test_sample_halving_factor() {
+        let mut sketch = build(SampleHalving { factor: 4 });
+        assert_eq!(sketch.sample_size, 64);
+        for i in 0..63u32 {
+            sketch.increment(&i);
+        }
+        assert_eq!((sketch.size, sketch.frequency(&0)), (63, 1));
+        sketch.increment(&63);
+        assert!(sketch.size < 32);
+        assert_eq!(sketch.frequency(&0), 0);
+    }
+
+    #[test]
+    fn test_manual_aging() {
+        let mut sketch = build(ManualAging);
+        for i in 0..100_000u32 {
+            sketch.increment(&i);
+        }
+        assert!(sketch.size > 16 * 80);
+        assert!(sketch.frequency(&0) > 0);
+        sketch.reset();
+        assert!(sketch.size <= 50_000);
+    }
+
+    #[test]
+    fn test_time_halving() {
+        for (interval, expected) in [(Duration::from_secs(3600), 10), (Duration::ZERO, 5)] {
+            let mut sketch = build(TimeHalving::new(interval));
+            for _ in 0..10 {
+                sketch.increment("key");
+            }
+            for i in 0..6u32 {
+                sketch.increment(&i);
+            }
+            assert_eq!(sketch.frequency("key"), expected);
+        }
+    }
+
+    #[test]
+    fn test_subtract_one_decay() {
+        let mut sketch = build(SubtractOneDecay { factor: 80 });
+        for i in 0..10u32 {
+            for _ in 0..i {
+                sketch.increment(&i);
+            }
+        }
+        let before: Vec<u8> = (0..10u32).map(|i| sketch.frequency(&i)).collect();
+        sketch.reset();
+        for (i, before) in (0..10u32).zip(before) {
+            assert_eq!(sketch.frequency(&i), before.saturating_sub(1));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+#[cfg(target_arch = "x86_64")]
+use super::intrinsics;
+use super::{portable, CacheLine};
+
+/// The instruction set used for the counter and aging kernels of a
+/// [`FrequencySketch`](super::FrequencySketch).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// Pure-Rust SWAR kernels, available on every target.
+    Portable,
+    /// SSE4.1 counter operations and SSE2 aging and merge.
+    Sse41,
+    /// SSE4.1 counter operations and AVX2 aging and merge.
+    Avx2,
+    /// SSE4.1 counter operations and AVX-512BW aging and merge. Requires the `stdavx512` feature.
+    Avx512,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 4] = [
+        Backend::Portable,
+        Backend::Sse41,
+        Backend::Avx2,
+        Backend::Avx512,
+    ];
+
+    /// Returns the fastest backend supported by the running CPU.
+    ///
+    /// With the `portable` feature enabled this always returns [`Backend::Portable`].
+    pub fn detect() -> Backend {
+        if cfg!(feature = "portable") {
+            return Backend::Portable;
+        }
+        Backend::ALL
+            .iter()
+            .rev()
+            .copied()
+            .find(|backend| backend.is_supported())
+            .unwrap_or(Backend::Portable)
+    }
+
+    /// Returns whether the running CPU can execute this backend.
+    pub fn is_supported(self) -> bool {
+        cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                match self {
+                    Backend::Portable => true,
+                    Backend::Sse41 => is_x86_feature_detected!("sse4.1"),
+                    Backend::Avx2 => {
+                        is_x86_feature_detected!("sse4.1") && is_x86_feature_detected!("avx2")
+                    }
+                    Backend::Avx512 => {
+                        cfg!(feature = "stdavx512")
+                            && is_x86_feature_detected!("sse4.1")
+                            && is_x86_feature_detected!("avx512f")
+                            && is_x86_feature_detected!("avx512bw")
+                    }
+                }
+            } else {
+                self == Backend::Portable
+            }
+        }
+    }
+
+    pub(super) fn kernels(self) -> Kernels {
+        assert!(
+            self.is_supported(),
+            "{:?} is not supported by this CPU",
+            self
+        );
+        let portable = Kernels {
+            backend: Backend::Portable,
+            frequency: portable::frequency,
+            increment: portable::increment,
+            conservative_increment: portable::conservative_increment,
+            contains: portable::contains,
+            reset: portable::reset,
+            decrement: portable::decrement,
+            merge: portable::merge,
+            clear: portable::clear,
+        };
+        cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                let sse41 = Kernels {
+                    backend: Backend::Sse41,
+                    frequency: intrinsics::frequency,
+                    increment: intrinsics::increment,
+                    conservative_increment: intrinsics::conservative_increment,
+                    contains: intrinsics::contains,
+                    reset: intrinsics::reset_sse2,
+                    decrement: intrinsics::decrement_sse2,
+                    merge: intrinsics::merge_sse2,
+                    clear: intrinsics::clear_sse2,
+                };
+                match self {
+                    Backend::Portable => portable,
+                    Backend::Sse41 => sse41,
+                    Backend::Avx2 => Kernels {
+                        backend: self,
+                        reset: intrinsics::reset_avx2,
+                        decrement: intrinsics::decrement_avx2,
+                        merge: intrinsics::merge_avx2,
+                        clear: intrinsics::clear_avx2,
+                        ..sse41
+                    },
+                    #[cfg(feature = "stdavx512")]
+                    Backend::Avx512 => Kernels {
+                        backend: self,
+                        reset: intrinsics::reset_avx512,
+                        decrement: intrinsics::decrement_avx512,
+                        merge: intrinsics::merge_avx512,
+                        clear: intrinsics::clear_avx512,
+                        ..sse41
+                    },
+                    #[cfg(not(feature = "stdavx512"))]
+                    Backend::Avx512 => unreachable!(),
+                }
+            } else {
+                portable
+            }
+        }
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::detect()
+    }
+}
+
+type FrequencyFn = unsafe fn((u64, u64), (u16, u16)) -> u8;
+type IncrementFn = unsafe fn((&mut u64, &mut u64), (u16, u16)) -> (u8, bool);
+type ContainsFn = unsafe fn((u64, u64), (u16, u16), u32) -> bool;
+type ResetFn = unsafe fn(&mut CacheLine) -> u8;
+type MergeFn = unsafe fn(&mut CacheLine, &CacheLine);
+type ClearFn = unsafe fn(&mut [CacheLine]);
+
+/// Function table resolved from a supported [`Backend`].
+///
+/// Only [`Backend::kernels`] constructs it, after checking CPU support, which is what makes
+/// calling through the `unsafe fn` pointers sound.
+#[derive(Copy, Clone)]
+pub(super) struct Kernels {
+    backend: Backend,
+    frequency: FrequencyFn,
+    increment: IncrementFn,
+    conservative_increment: IncrementFn,
+    contains: ContainsFn,
+    reset: ResetFn,
+    decrement: ResetFn,
+    merge: MergeFn,
+    clear: ClearFn,
+}
+
+impl Kernels {
+    pub(super) fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub(super) fn frequency(&self, blocks: (u64, u64), masks: (u16, u16)) -> u8 {
+        unsafe { (self.frequency)(blocks, masks) }
+    }
+
+    pub(super) fn increment(&self, blocks: (&mut u64, &mut u64), masks: (u16, u16)) -> (u8, bool) {
+        unsafe { (self.increment)(blocks, masks) }
+    }
+
+    pub(super) fn conservative_increment(
+        &self,
+        blocks: (&mut u64, &mut u64),
+        masks: (u16, u16),
+    ) -> (u8, bool) {
+        unsafe { (self.conservative_increment)(blocks, masks) }
+    }
+
+    pub(super) fn contains(&self, blocks: (u64, u64), masks: (u16, u16), offset: u32) -> bool {
+        unsafe { (self.contains)(blocks, masks, offset) }
+    }
+
+    pub(super) fn reset(&self, cache_line: &mut CacheLine) -> u8 {
+        unsafe { (self.reset)(cache_line) }
+    }
+
+    pub(super) fn decrement(&self, cache_line: &mut CacheLine) -> u8 {
+        unsafe { (self.decrement)(cache_line) }
+    }
+
+    pub(super) fn merge(&self, cache_line: &mut CacheLine, other: &CacheLine) {
+        unsafe { (self.merge)(cache_line, other) }
+    }
+
+    pub(super) fn clear(&self, cache_lines: &mut [CacheLine]) {
+        unsafe { (self.clear)(cache_lines) }
+    }
+}
@@ -0,0 +1,187 @@
+use super::{
+    cache_line_index, make_hash, prefetch, reduce, try_zeroed_lines, Backend, CacheLine, Kernels,
+    SketchError,
+};
+use ahash::RandomState;
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use std::hash::{BuildHasher, Hash};
+
+/// A cache-line-blocked Bloom filter sharing the [`FrequencySketch`](super::FrequencySketch)
+/// layout.
+///
+/// A key selects one 64-byte cache line, two of its eight blocks, and four bits in each block, so
+/// every `insert` and `contains` touches a single cache line.
+#[derive(Clone)]
+pub struct BlockedBloomFilter<S = RandomState, A: Allocator = Global> {
+    pub(super) lines: Box<[CacheLine], A>,
+    pub(super) len: usize,
+    hash_builder: S,
+    kernels: Kernels,
+}
+
+/// Selects which of the four bits of each of the key's counters it sets, after the cache line,
+/// blocks and masks consumed their hash bits.
+pub(super) fn bit_offset(hash: &mut u64) -> u32 {
+    reduce(hash, 4) as u32
+}
+
+impl BlockedBloomFilter<RandomState, Global> {
+    pub fn with_capacity(lines: usize) -> Self {
+        Self::with_capacity_and_hasher_in(lines, RandomState::new(), Global)
+    }
+}
+
+impl<S, A: Allocator> BlockedBloomFilter<S, A> {
+    /// # Panics
+    ///
+    /// Panics if `lines` is zero or greater than 2^35, and aborts if the allocation fails.
+    pub fn with_capacity_and_hasher_in(lines: usize, hasher: S, alloc: A) -> Self {
+        Self::try_with_capacity_and_hasher_in(lines, hasher, alloc)
+            .unwrap_or_else(|err| err.raise())
+    }
+
+    /// Like [`with_capacity_and_hasher_in`](Self::with_capacity_and_hasher_in), returning an
+    /// error instead of panicking or aborting.
+    pub fn try_with_capacity_and_hasher_in(
+        lines: usize,
+        hasher: S,
+        alloc: A,
+    ) -> Result<Self, SketchError> {
+        Ok(Self {
+            lines: try_zeroed_lines(lines, alloc)?,
+            len: 0,
+            hash_builder: hasher,
+            kernels: Backend::detect().kernels(),
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.kernels.backend()
+    }
+
+    /// Forces the filter to use `backend` instead of the one detected at construction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the running CPU does not support `backend`.
+    pub fn force_backend(&mut self, backend: Backend) {
+        self.kernels = backend.kernels();
+    }
+
+    /// Returns the number of keys that set at least one new bit when inserted.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.kernels.clear(&mut self.lines);
+        self.len = 0;
+    }
+
+    /// Estimates the false positive rate for the current number of keys.
+    ///
+    /// A key sets four of the sixteen bits sharing one of four offsets in two of the eight blocks
+    /// of its cache line, so the keys competing for those bits are Poisson distributed with mean
+    /// `len / (16 * lines)`.
+    pub fn false_positive_rate(&self) -> f64 {
+        let lambda = self.len as f64 / (16 * self.lines.len()) as f64;
+        let terms = (2.0 * lambda) as i32 + 64;
+        let (mut poisson, mut rate) = ((-lambda).exp(), 0.0);
+        for k in 0..terms {
+            let fill = 1.0 - 0.75f64.powi(k);
+            rate += poisson * fill.powi(4);
+            poisson *= lambda / (k + 1) as f64;
+        }
+        rate * rate
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    /// Like [`contains`](Self::contains), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn contains_hash(&self, hash: u64) -> bool {
+        let hash = &mut { hash };
+        let index = cache_line_index(hash, self.lines.len());
+        self.lines[index].contains(hash, &self.kernels)
+    }
+
+    /// Sets every bit set in `other`. `len` becomes the sum of both lengths, an upper bound.
+    pub(super) fn union<T, B: Allocator>(&mut self, other: &BlockedBloomFilter<T, B>) {
+        assert_eq!(self.lines.len(), other.lines.len(), "filter sizes differ");
+        for (line, other) in self.lines.iter_mut().zip(other.lines.iter()) {
+            for (block, other) in line.0.iter_mut().zip(other.0.iter()) {
+                *block |= other;
+            }
+        }
+        self.len = self.len.saturating_add(other.len);
+    }
+
+    pub(super) fn prefetch_hash(&self, hash: u64) {
+        prefetch(&self.lines[cache_line_index(&mut { hash }, self.lines.len())]);
+    }
+
+    /// Like [`insert`](Self::insert), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn insert_hash(&mut self, hash: u64) -> bool {
+        let hash = &mut { hash };
+        let index = cache_line_index(hash, self.lines.len());
+        let inserted = self.lines[index].insert(hash);
+        self.len += inserted as usize;
+        inserted
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> BlockedBloomFilter<S, A> {
+    /// Adds `key` to the filter, returning `true` if it was not already (possibly) present.
+    pub fn insert<Q: Hash + ?Sized>(&mut self, key: &Q) -> bool {
+        self.insert_hash(make_hash(&self.hash_builder, key))
+    }
+
+    /// Returns `false` if `key` was definitely never inserted.
+    pub fn contains<Q: Hash + ?Sized>(&self, key: &Q) -> bool {
+        self.contains_hash(make_hash(&self.hash_builder, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let mut filter = BlockedBloomFilter::with_capacity(64);
+        for i in 0..4096 {
+            filter.insert(&i);
+        }
+        assert!((0..4096).all(|i| filter.contains(&i)));
+        assert!(!filter.insert(&0));
+        filter.clear();
+        assert!(filter.is_empty());
+        assert!((0..4096).all(|i| !filter.contains(&i)));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let mut filter = BlockedBloomFilter::with_capacity(1024);
+        for i in 0..32_768 {
+            filter.insert(&i);
+        }
+        let trials = 1_000_000;
+        let positives = (32_768..32_768 + trials)
+            .filter(|i| filter.contains(i))
+            .count();
+        let measured = positives as f64 / trials as f64;
+        let estimated = filter.false_positive_rate();
+        assert!(
+            (measured - estimated).abs() < estimated * 0.25,
+            "measured {} estimated {}",
+            measured,
+            estimated
+        );
+    }
+}
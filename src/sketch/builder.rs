@@ -0,0 +1,224 @@
+use super::{
+    max_lines, AgingPolicy, Backend, BlockedBloomFilter, FrequencySketch, IncrementalReset,
+    SketchError, UpdateMode,
+};
+use ahash::RandomState;
+use allocator_api2::alloc::{Allocator, Global};
+use std::hash::BuildHasher;
+
+const DOORKEEPER_BITS_PER_ENTRY: usize = 8;
+
+/// Configures and creates a [`FrequencySketch`].
+pub struct FrequencySketchBuilder<S = RandomState, A = Global> {
+    sketch_size: usize,
+    hasher: S,
+    alloc: A,
+    backend: Option<Backend>,
+    doorkeeper: Option<usize>,
+    update_mode: UpdateMode,
+    aging: Option<Box<dyn AgingPolicy>>,
+    incremental_reset: Option<usize>,
+}
+
+impl FrequencySketchBuilder {
+    /// Starts a builder for a sketch of `sketch_size` cache lines.
+    pub fn new(sketch_size: usize) -> Self {
+        Self {
+            sketch_size,
+            hasher: RandomState::new(),
+            alloc: Global,
+            backend: None,
+            doorkeeper: None,
+            update_mode: UpdateMode::Standard,
+            aging: None,
+            incremental_reset: None,
+        }
+    }
+}
+
+impl<S, A> FrequencySketchBuilder<S, A> {
+    pub fn hasher<T: BuildHasher>(self, hasher: T) -> FrequencySketchBuilder<T, A> {
+        FrequencySketchBuilder {
+            sketch_size: self.sketch_size,
+            hasher,
+            alloc: self.alloc,
+            backend: self.backend,
+            doorkeeper: self.doorkeeper,
+            update_mode: self.update_mode,
+            aging: self.aging,
+            incremental_reset: self.incremental_reset,
+        }
+    }
+
+    pub fn allocator<B: Allocator>(self, alloc: B) -> FrequencySketchBuilder<S, B> {
+        FrequencySketchBuilder {
+            sketch_size: self.sketch_size,
+            hasher: self.hasher,
+            alloc,
+            backend: self.backend,
+            doorkeeper: self.doorkeeper,
+            update_mode: self.update_mode,
+            aging: self.aging,
+            incremental_reset: self.incremental_reset,
+        }
+    }
+
+    /// Uses `backend` instead of the one detected from the running CPU.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Selects how increments update the counters, [`UpdateMode::Standard`] by default.
+    pub fn update_mode(mut self, update_mode: UpdateMode) -> Self {
+        self.update_mode = update_mode;
+        self
+    }
+
+    /// Ages the counters with `aging` instead of halving them every 80 samples per cache line.
+    ///
+    /// The policy is boxed with the global allocator, not the one set through
+    /// [`allocator`](Self::allocator).
+    pub fn aging<P: AgingPolicy + 'static>(mut self, aging: P) -> Self {
+        self.aging = Some(Box::new(aging));
+        self
+    }
+
+    /// Spreads every [`reset`](FrequencySketch::reset) over the following increments, each aging
+    /// `lines_per_increment` cache lines, instead of aging the whole sketch inside the increment
+    /// that crossed the sample size.
+    ///
+    /// Estimates are the same as with a serial reset throughout. A reset still running when the
+    /// next one starts is finished at once, which one line per increment rules out with the
+    /// default aging policy.
+    pub fn incremental_reset(mut self, lines_per_increment: usize) -> Self {
+        self.incremental_reset = Some(lines_per_increment);
+        self
+    }
+
+    /// Puts a doorkeeper Bloom filter sized for `entries` distinct keys per sample period in
+    /// front of the counters.
+    ///
+    /// The first occurrence of a key only sets its doorkeeper bits, so one-hit wonders never
+    /// touch the counters, and every estimate includes the key's doorkeeper bit. Both are
+    /// cleared on [`FrequencySketch::reset`].
+    pub fn doorkeeper(mut self, entries: usize) -> Self {
+        self.doorkeeper = Some(entries);
+        self
+    }
+}
+
+impl<S: BuildHasher, A: Allocator + Clone> FrequencySketchBuilder<S, A> {
+    /// # Panics
+    ///
+    /// Panics if the sketch size is zero or greater than 2^35, if an incremental reset ages
+    /// zero lines per increment, or if the requested backend is not supported by the running CPU,
+    /// and aborts if an allocation fails. See [`try_build`](Self::try_build).
+    pub fn build(self) -> FrequencySketch<S, A> {
+        self.try_build().unwrap_or_else(|err| err.raise())
+    }
+
+    /// Like [`build`](Self::build), returning an error instead of panicking or aborting.
+    pub fn try_build(self) -> Result<FrequencySketch<S, A>, SketchError> {
+        if let Some(backend) = self.backend.filter(|backend| !backend.is_supported()) {
+            return Err(SketchError::UnsupportedBackend(backend));
+        }
+        if self.incremental_reset == Some(0) {
+            return Err(SketchError::InvalidResetStep);
+        }
+        let mut sketch = FrequencySketch::try_with_capacity_and_hasher_in(
+            self.sketch_size,
+            self.hasher,
+            self.alloc.clone(),
+        )?;
+        if let Some(entries) = self.doorkeeper {
+            let lines = entries
+                .saturating_mul(DOORKEEPER_BITS_PER_ENTRY)
+                .div_ceil(512)
+                .clamp(1, max_lines());
+            let doorkeeper =
+                BlockedBloomFilter::try_with_capacity_and_hasher_in(lines, (), self.alloc.clone())?;
+            sketch.doorkeeper = Some(doorkeeper);
+        }
+        sketch.update_mode = self.update_mode;
+        if let Some(aging) = self.aging {
+            sketch.sample_size = aging.sample_size(sketch.sketch.len());
+            sketch.aging = aging;
+        }
+        if let Some(step) = self.incremental_reset {
+            let lines = sketch.sketch.len();
+            sketch.incremental = Some(IncrementalReset::try_new(lines, step, self.alloc)?);
+        }
+        if let Some(backend) = self.backend {
+            sketch.force_backend(backend);
+        }
+        Ok(sketch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Failing;
+    use super::*;
+    use allocator_api2::alloc::AllocError;
+    use std::alloc::Layout;
+    use std::ptr::NonNull;
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::sync::Arc;
+
+    /// Tracks the bytes currently allocated through it.
+    #[derive(Clone, Default)]
+    struct Counting(Arc<AtomicUsize>);
+
+    unsafe impl Allocator for Counting {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.0.fetch_add(layout.size(), Relaxed);
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.0.fetch_sub(layout.size(), Relaxed);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn test_try_build() {
+        let build = |size| FrequencySketch::builder(size).try_build().map(|_| ());
+        assert_eq!(build(0), Err(SketchError::InvalidSize(0)));
+        assert_eq!(
+            build((1 << 35) + 1),
+            Err(SketchError::InvalidSize((1 << 35) + 1))
+        );
+        assert!(build(1).is_ok());
+        let sketch = FrequencySketch::builder(16)
+            .incremental_reset(0)
+            .try_build();
+        assert_eq!(sketch.err(), Some(SketchError::InvalidResetStep));
+        let saturated = SketchError::AllocationFailed { bytes: usize::MAX };
+        assert!(std::panic::catch_unwind(|| saturated.raise()).is_err());
+        let sketch = FrequencySketch::builder(16).allocator(Failing).try_build();
+        assert_eq!(
+            sketch.err(),
+            Some(SketchError::AllocationFailed { bytes: 16 * 64 })
+        );
+        let unsupported = Backend::ALL.iter().find(|backend| !backend.is_supported());
+        if let Some(&backend) = unsupported {
+            let sketch = FrequencySketch::builder(16).backend(backend).try_build();
+            assert_eq!(sketch.err(), Some(SketchError::UnsupportedBackend(backend)));
+        }
+    }
+
+    #[test]
+    fn test_allocator() {
+        let alloc = Counting::default();
+        let sketch = FrequencySketch::builder(16)
+            .allocator(alloc.clone())
+            .doorkeeper(512)
+            .incremental_reset(1)
+            .build();
+        assert_eq!(alloc.0.load(Relaxed), (16 + 8) * 64 + 8);
+        drop(sketch);
+        assert_eq!(alloc.0.load(Relaxed), 0);
+    }
+}
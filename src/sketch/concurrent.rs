@@ -0,0 +1,213 @@
+use super::{
+    block_indices_h, block_masks, cache_line_index, make_hash, portable, try_zeroed_lines,
+    AgingPolicy, Backend, Kernels, SampleHalving, SketchError,
+};
+use ahash::RandomState;
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+
+#[repr(C, align(64))]
+struct AtomicCacheLine([AtomicU64; 8]);
+
+unsafe impl bytemuck::Zeroable for AtomicCacheLine {}
+
+/// A [`FrequencySketch`](super::FrequencySketch) that can be shared between threads.
+///
+/// Every block is an [`AtomicU64`] updated with a compare-and-swap loop, so concurrent
+/// increments of the same block are never lost. Reads never block: the thread whose increment
+/// crosses the sample size halves the counters block by block while others keep going, and only
+/// one reset runs at a time.
+pub struct ConcurrentFrequencySketch<S = RandomState, A: Allocator = Global> {
+    sketch: Box<[AtomicCacheLine], A>,
+    size: AtomicUsize,
+    sample_size: usize,
+    resetting: AtomicBool,
+    hash_builder: S,
+    kernels: Kernels,
+}
+
+impl ConcurrentFrequencySketch<RandomState, Global> {
+    pub fn with_capacity(sketch_size: usize) -> Self {
+        Self::with_capacity_and_hasher_in(sketch_size, RandomState::new(), Global)
+    }
+
+    /// Like [`with_capacity`](Self::with_capacity), returning an error instead of panicking or
+    /// aborting.
+    pub fn try_with_capacity(sketch_size: usize) -> Result<Self, SketchError> {
+        Self::try_with_capacity_and_hasher_in(sketch_size, RandomState::new(), Global)
+    }
+}
+
+impl<S, A: Allocator> ConcurrentFrequencySketch<S, A> {
+    /// # Panics
+    ///
+    /// Panics if `sketch_size` is zero or greater than 2^35, and aborts if the allocation
+    /// fails. See [`try_with_capacity_and_hasher_in`](Self::try_with_capacity_and_hasher_in).
+    pub fn with_capacity_and_hasher_in(sketch_size: usize, hasher: S, alloc: A) -> Self {
+        Self::try_with_capacity_and_hasher_in(sketch_size, hasher, alloc)
+            .unwrap_or_else(|err| err.raise())
+    }
+
+    /// Creates a sketch of `sketch_size` cache lines, failing with
+    /// [`SketchError::InvalidSize`] if it is zero or greater than 2^35, or with
+    /// [`SketchError::AllocationFailed`] if `alloc` can't provide the counters.
+    pub fn try_with_capacity_and_hasher_in(
+        sketch_size: usize,
+        hasher: S,
+        alloc: A,
+    ) -> Result<Self, SketchError> {
+        Ok(Self {
+            sketch: try_zeroed_lines(sketch_size, alloc)?,
+            size: AtomicUsize::new(0),
+            sample_size: SampleHalving::default().sample_size(sketch_size),
+            resetting: AtomicBool::new(false),
+            hash_builder: hasher,
+            kernels: Backend::detect().kernels(),
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.kernels.backend()
+    }
+
+    /// Forces the sketch to use `backend` instead of the one detected at construction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the running CPU does not support `backend`.
+    pub fn force_backend(&mut self, backend: Backend) {
+        self.kernels = backend.kernels();
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    fn blocks(&self, hash: u64) -> ((&AtomicU64, &AtomicU64), (u16, u16)) {
+        let hash = &mut { hash };
+        let cache_line = &self.sketch[cache_line_index(hash, self.sketch.len())];
+        let (idx_1, idx_2) = block_indices_h(hash);
+        let blocks = (&cache_line.0[idx_1], &cache_line.0[idx_2]);
+        (blocks, block_masks(hash))
+    }
+
+    /// Like [`frequency`](Self::frequency), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn frequency_hash(&self, hash: u64) -> u8 {
+        let ((x, y), masks) = self.blocks(hash);
+        self.kernels
+            .frequency((x.load(Relaxed), y.load(Relaxed)), masks)
+    }
+
+    /// Like [`increment`](Self::increment), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn increment_hash(&self, hash: u64) -> u8 {
+        let ((x, y), masks) = self.blocks(hash);
+        let [x_mask, y_mask] = portable::mask_deinterleave(masks.0, masks.1);
+        let increment = |block: &AtomicU64, mask| {
+            let update = |num| Some(portable::mask_saturating_increment(num, mask));
+            let update = |num| update(num).filter(|&inc| inc != num);
+            block
+                .fetch_update(Relaxed, Relaxed, update)
+                .unwrap_or_else(|num| num)
+        };
+        let min = self
+            .kernels
+            .frequency((increment(x, x_mask), increment(y, y_mask)), masks);
+        let saturated = min == 0xF;
+        if !saturated && self.size.fetch_add(1, Relaxed) + 1 >= self.sample_size {
+            self.try_reset();
+        }
+        min + !saturated as u8
+    }
+
+    /// Halves every counter unless another thread is already doing so, returning whether this
+    /// call performed the reset.
+    pub fn try_reset(&self) -> bool {
+        if self.resetting.swap(true, Acquire) {
+            return false;
+        }
+        let mut count = 0;
+        for block in self
+            .sketch
+            .iter()
+            .flat_map(|cache_line| cache_line.0.iter())
+        {
+            let halve = |num| Some(portable::reset_block(num).0);
+            let num = block.fetch_update(Relaxed, Relaxed, halve).unwrap();
+            count += portable::reset_block(num).1 as usize;
+        }
+        let halve = |size: usize| Some((size >> 1).saturating_sub(count >> 2));
+        let _ = self.size.fetch_update(Relaxed, Relaxed, halve);
+        self.resetting.store(false, Release);
+        true
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> ConcurrentFrequencySketch<S, A> {
+    pub fn frequency<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.frequency_hash(make_hash(&self.hash_builder, key))
+    }
+
+    pub fn increment<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.increment_hash(make_hash(&self.hash_builder, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{seeded, Failing};
+    use super::*;
+    use rand::Rng;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_try_with_capacity() {
+        let sketch = ConcurrentFrequencySketch::try_with_capacity(0);
+        assert_eq!(sketch.err(), Some(SketchError::InvalidSize(0)));
+        let sketch = ConcurrentFrequencySketch::try_with_capacity_and_hasher_in(16, (), Failing);
+        assert_eq!(
+            sketch.err(),
+            Some(SketchError::AllocationFailed { bytes: 16 * 64 })
+        );
+        assert!(ConcurrentFrequencySketch::try_with_capacity(16).is_ok());
+    }
+
+    #[test]
+    fn test_matches_frequency_sketch() {
+        let mut reference = seeded(64).build();
+        let hasher = reference.hasher().clone();
+        let concurrent = ConcurrentFrequencySketch::with_capacity_and_hasher_in(64, hasher, Global);
+        let mut rng = rand::thread_rng();
+        for _ in 0..64 * 200 {
+            let key: u16 = rng.gen_range(0..1024);
+            assert_eq!(concurrent.increment(&key), reference.increment(&key));
+            assert_eq!(concurrent.frequency(&key), reference.frequency(&key));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_increments() {
+        const THREADS: u32 = 4;
+        let sketch = Arc::new(ConcurrentFrequencySketch::with_capacity(1024));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let sketch = Arc::clone(&sketch);
+                thread::spawn(move || {
+                    for i in 0..100_000u32 {
+                        sketch.increment(&(i % 64 + t * 64));
+                        sketch.increment(&u32::MAX);
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .for_each(|handle| handle.join().unwrap());
+        assert!(sketch.frequency(&u32::MAX) >= 7);
+        assert!(sketch.size.load(Relaxed) < sketch.sample_size);
+        assert!(!sketch.resetting.load(Relaxed));
+    }
+}
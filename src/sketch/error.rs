@@ -0,0 +1,64 @@
+use super::Backend;
+use std::alloc::{handle_alloc_error, Layout};
+use std::error::Error;
+use std::fmt;
+
+/// Why a sketch or filter couldn't be created.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SketchError {
+    /// A size was zero, or a cache line count greater than 2^35.
+    InvalidSize(usize),
+    /// The allocator couldn't provide `bytes` bytes for the cache lines.
+    AllocationFailed { bytes: usize },
+    /// The requested backend isn't supported by the running CPU.
+    UnsupportedBackend(Backend),
+    /// A shard count was zero or greater than the number of cache lines to spread over.
+    InvalidShards(usize),
+    /// An incremental reset was configured to age zero cache lines per increment.
+    InvalidResetStep,
+}
+
+impl SketchError {
+    /// Fails the way the infallible constructors always have: allocation failures go through
+    /// [`handle_alloc_error`], everything else, including requests too large to describe with a
+    /// [`Layout`], panics.
+    pub(super) fn raise(self) -> ! {
+        if let SketchError::AllocationFailed { bytes } = self {
+            if let Ok(layout) = Layout::from_size_align(bytes, 64) {
+                handle_alloc_error(layout)
+            }
+        }
+        panic!("{}", self)
+    }
+}
+
+impl fmt::Display for SketchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SketchError::InvalidSize(size) => {
+                write!(f, "invalid size {}, expected 0 < size <= 2^35", size)
+            }
+            SketchError::AllocationFailed { bytes } => {
+                write!(f, "failed to allocate {} bytes of cache lines", bytes)
+            }
+            SketchError::UnsupportedBackend(backend) => {
+                write!(f, "{:?} is not supported by this CPU", backend)
+            }
+            SketchError::InvalidShards(shards) => {
+                write!(
+                    f,
+                    "invalid shard count {}, expected 0 < shards <= lines",
+                    shards
+                )
+            }
+            SketchError::InvalidResetStep => {
+                write!(
+                    f,
+                    "an incremental reset must age at least one line per increment"
+                )
+            }
+        }
+    }
+}
+
+impl Error for SketchError {}
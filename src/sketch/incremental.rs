@@ -0,0 +1,128 @@
+use super::{try_zeroed_lines, CacheLine, Decay, Kernels, SketchError};
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+
+/// The state of a reset spread over the increments following it.
+///
+/// Every line carries an epoch bit, flipped when the line is aged. A reset flips the current
+/// epoch, turning every line stale, and a line is aged either when the cursor passes it or when an
+/// increment touches it first, whichever comes first. Estimates read from a stale line are aged on
+/// the fly, so they never depend on how far the reset got.
+#[derive(Clone)]
+pub(super) struct IncrementalReset<A: Allocator = Global> {
+    step: usize,
+    cursor: usize,
+    lines: usize,
+    epoch: bool,
+    epochs: Box<[u64], A>,
+    decay: Decay,
+    count: usize,
+}
+
+impl<A: Allocator> IncrementalReset<A> {
+    /// Allocates the epoch bits of `lines` cache lines with `alloc`.
+    pub(super) fn try_new(lines: usize, step: usize, alloc: A) -> Result<Self, SketchError> {
+        Ok(Self {
+            step,
+            cursor: lines,
+            lines,
+            epoch: false,
+            epochs: try_zeroed_lines(lines.div_ceil(64), alloc)?,
+            decay: Decay::Halve,
+            count: 0,
+        })
+    }
+
+    /// Drops any unfinished reset and marks every line fresh.
+    pub(super) fn clear(&mut self) {
+        self.epochs.fill(0);
+        self.epoch = false;
+        self.cursor = self.lines;
+    }
+
+    pub(super) fn step(&self) -> usize {
+        self.step
+    }
+
+    pub(super) fn memory_bytes(&self) -> usize {
+        self.epochs.len() * std::mem::size_of::<u64>()
+    }
+
+    pub(super) fn is_pending(&self) -> bool {
+        self.cursor < self.lines
+    }
+
+    pub(super) fn is_stale(&self, index: usize) -> bool {
+        self.is_pending() && (self.epochs[index / 64] >> (index % 64) & 1 == 1) != self.epoch
+    }
+
+    /// Turns every line stale. The previous reset must be finished.
+    pub(super) fn start(&mut self, decay: Decay) {
+        debug_assert!(!self.is_pending());
+        self.epoch = !self.epoch;
+        self.cursor = 0;
+        self.decay = decay;
+        self.count = 0;
+    }
+
+    /// Ages the line at `index` if it is stale, returning the samples forgotten.
+    pub(super) fn age_line(
+        &mut self,
+        index: usize,
+        cache_line: &mut CacheLine,
+        kernels: &Kernels,
+    ) -> usize {
+        if !self.is_stale(index) {
+            return 0;
+        }
+        self.epochs[index / 64] ^= 1 << (index % 64);
+        let forgotten = self.decay.forgotten(self.count);
+        self.count += self.decay.apply(kernels, cache_line) as usize;
+        self.decay.forgotten(self.count) - forgotten
+    }
+
+    /// Ages up to `lines` lines at the cursor, returning the samples forgotten.
+    pub(super) fn advance(
+        &mut self,
+        sketch: &mut [CacheLine],
+        kernels: &Kernels,
+        lines: usize,
+    ) -> usize {
+        let end = self.cursor.saturating_add(lines).min(self.lines);
+        let mut forgotten = 0;
+        while self.cursor < end {
+            forgotten += self.age_line(self.cursor, &mut sketch[self.cursor], kernels);
+            self.cursor += 1;
+        }
+        forgotten
+    }
+
+    pub(super) fn estimate(&self, index: usize, frequency: u8) -> u8 {
+        if self.is_stale(index) {
+            self.decay.estimate(frequency)
+        } else {
+            frequency
+        }
+    }
+
+    /// Returns `cache_line` as it will be once the reset finishes, and [`Decay::apply`] for it.
+    pub(super) fn aged_line(
+        &self,
+        index: usize,
+        cache_line: &CacheLine,
+        kernels: &Kernels,
+    ) -> (CacheLine, usize) {
+        let mut cache_line = *cache_line;
+        let count = match self.is_stale(index) {
+            true => self.decay.apply(kernels, &mut cache_line) as usize,
+            false => 0,
+        };
+        (cache_line, count)
+    }
+
+    /// Returns the samples still to be forgotten once the lines not aged yet are, from the sum of
+    /// [`aged_line`](Self::aged_line) over them.
+    pub(super) fn forgotten(&self, count: usize) -> usize {
+        self.decay.forgotten(self.count + count) - self.decay.forgotten(self.count)
+    }
+}
@@ -9,12 +9,12 @@ union SseUnion {
 union CacheLineUnion {
     arr: CacheLine,
     sse: [__m128i; 4],
-    #[cfg(target_feature = "avx2")]
     avx2: [__m256i; 2],
     #[cfg(feature = "stdavx512")]
     avx512: __m512i,
 }
 
+#[target_feature(enable = "sse4.1")]
 unsafe fn mask_deinterleave(x_mask: u16, y_mask: u16) -> SseUnion {
     let mut register = _mm_setr_epi16(x_mask as _, 0, 0, 0, y_mask as _, 0, 0, 0);
     register = _mm_or_si128(register, _mm_slli_epi64::<15>(register));
@@ -23,6 +23,7 @@ unsafe fn mask_deinterleave(x_mask: u16, y_mask: u16) -> SseUnion {
     SseUnion { sse }
 }
 
+#[target_feature(enable = "sse4.1")]
 unsafe fn mask_min(num: __m128i, mask: __m128i) -> u8 {
     let mut register = _mm_mullo_epi16(mask, _mm_set1_epi16(0xF));
     register = _mm_xor_si128(register, _mm_set1_epi8(-1));
@@ -32,6 +33,7 @@ unsafe fn mask_min(num: __m128i, mask: __m128i) -> u8 {
     (_mm_cvtsi128_si32(_mm_minpos_epu16(register)) >> 4) as _
 }
 
+#[target_feature(enable = "sse4.1")]
 unsafe fn mask_saturating_increment(num: __m128i, mask: __m128i) -> SseUnion {
     let mut register = _mm_and_si128(num, _mm_srli_epi16::<2>(num));
     register = _mm_and_si128(register, _mm_srli_epi16::<1>(register));
@@ -39,29 +41,67 @@ unsafe fn mask_saturating_increment(num: __m128i, mask: __m128i) -> SseUnion {
     SseUnion { sse }
 }
 
-pub(super) fn frequency((x, y): (u64, u64), (x_mask, y_mask): (u16, u16)) -> u8 {
-    unsafe {
-        let num = _mm_set_epi64x(y as i64, x as i64);
-        let mask = mask_deinterleave(x_mask, y_mask).sse;
-        mask_min(num, mask)
-    }
+#[target_feature(enable = "sse4.1")]
+unsafe fn mask_increment_eq(num: __m128i, mask: __m128i, min: u8) -> SseUnion {
+    let mut register = _mm_xor_si128(num, _mm_set1_epi8((min * 0x11) as _));
+    register = _mm_or_si128(register, _mm_srli_epi16::<2>(register));
+    register = _mm_or_si128(register, _mm_srli_epi16::<1>(register));
+    let sse = _mm_add_epi8(num, _mm_andnot_si128(register, mask));
+    SseUnion { sse }
 }
 
-pub(super) fn increment((x, y): (&mut u64, &mut u64), (x_mask, y_mask): (u16, u16)) -> (u8, bool) {
-    let min;
-    let [inc_x, inc_y] = unsafe {
-        let num = _mm_set_epi64x(*y as i64, *x as i64);
-        let mask = mask_deinterleave(x_mask, y_mask).sse;
-        min = mask_min(num, mask);
-        mask_saturating_increment(num, mask).arr
-    };
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn frequency((x, y): (u64, u64), (x_mask, y_mask): (u16, u16)) -> u8 {
+    let num = _mm_set_epi64x(y as i64, x as i64);
+    let mask = mask_deinterleave(x_mask, y_mask).sse;
+    mask_min(num, mask)
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn increment(
+    (x, y): (&mut u64, &mut u64),
+    (x_mask, y_mask): (u16, u16),
+) -> (u8, bool) {
+    let num = _mm_set_epi64x(*y as i64, *x as i64);
+    let mask = mask_deinterleave(x_mask, y_mask).sse;
+    let min = mask_min(num, mask);
+    let [inc_x, inc_y] = mask_saturating_increment(num, mask).arr;
     *x = inc_x;
     *y = inc_y;
     let full_sat = min == 0xF;
     (min + !full_sat as u8, full_sat)
 }
 
-unsafe fn reset_sse2(cache_line: &mut CacheLine) -> u8 {
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn conservative_increment(
+    (x, y): (&mut u64, &mut u64),
+    (x_mask, y_mask): (u16, u16),
+) -> (u8, bool) {
+    let num = _mm_set_epi64x(*y as i64, *x as i64);
+    let mask = mask_deinterleave(x_mask, y_mask).sse;
+    let min = mask_min(num, mask);
+    let full_sat = min == 0xF;
+    if !full_sat {
+        let [inc_x, inc_y] = mask_increment_eq(num, mask, min).arr;
+        *x = inc_x;
+        *y = inc_y;
+    }
+    (min + !full_sat as u8, full_sat)
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn contains(
+    (x, y): (u64, u64),
+    (x_mask, y_mask): (u16, u16),
+    offset: u32,
+) -> bool {
+    let num = _mm_set_epi64x(y as i64, x as i64);
+    let count = _mm_cvtsi32_si128(offset as i32);
+    let mask = _mm_sll_epi64(mask_deinterleave(x_mask, y_mask).sse, count);
+    _mm_testc_si128(num, mask) != 0
+}
+
+pub(super) unsafe fn reset_sse2(cache_line: &mut CacheLine) -> u8 {
     let mut sse2 = CacheLineUnion { arr: *cache_line }.sse;
     let mut counter = _mm_setzero_si128();
     for register in sse2.iter_mut() {
@@ -75,8 +115,8 @@ unsafe fn reset_sse2(cache_line: &mut CacheLine) -> u8 {
     (_mm_cvtsi128_si32(counter) + _mm_extract_epi16::<4>(counter)) as _
 }
 
-#[cfg(target_feature = "avx2")]
-unsafe fn reset_avx2(cache_line: &mut CacheLine) -> u8 {
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn reset_avx2(cache_line: &mut CacheLine) -> u8 {
     let mut avx2 = CacheLineUnion { arr: *cache_line }.avx2;
     let mut counter = _mm256_and_si256(avx2[0], _mm256_set1_epi8(0x11));
     counter = _mm256_add_epi8(counter, _mm256_and_si256(avx2[1], _mm256_set1_epi8(0x11)));
@@ -94,7 +134,8 @@ unsafe fn reset_avx2(cache_line: &mut CacheLine) -> u8 {
 }
 
 #[cfg(feature = "stdavx512")]
-unsafe fn reset_avx512(cache_line: &mut CacheLine) -> u8 {
+#[target_feature(enable = "avx512f,avx512bw")]
+pub(super) unsafe fn reset_avx512(cache_line: &mut CacheLine) -> u8 {
     let mut register = CacheLineUnion { arr: *cache_line }.avx512;
     let avx512 = _mm512_and_si512(_mm512_srli_epi64::<1>(register), _mm512_set1_epi8(0x77));
     *cache_line = CacheLineUnion { avx512 }.arr;
@@ -105,29 +146,156 @@ unsafe fn reset_avx512(cache_line: &mut CacheLine) -> u8 {
     _mm512_reduce_add_epi64(register) as _
 }
 
-pub(super) fn reset(cache_line: &mut CacheLine) -> u8 {
-    unsafe {
-        cfg_if! {
-            if #[cfg(all(
-                target_feature = "avx512bw",
-                target_feature = "avx512f",
-                feature = "stdavx512",
-            ))] {
-                reset_avx512(cache_line)
-            } else if #[cfg(target_feature = "avx2")] {
-                reset_avx2(cache_line)
-            } else {
-                reset_sse2(cache_line)
-            }
+pub(super) unsafe fn decrement_sse2(cache_line: &mut CacheLine) -> u8 {
+    let mut sse2 = CacheLineUnion { arr: *cache_line }.sse;
+    let mut counter = _mm_setzero_si128();
+    for register in sse2.iter_mut() {
+        let mut nonzero = _mm_or_si128(*register, _mm_srli_epi16::<2>(*register));
+        nonzero = _mm_or_si128(nonzero, _mm_srli_epi16::<1>(nonzero));
+        nonzero = _mm_and_si128(nonzero, _mm_set1_epi8(0x11));
+        counter = _mm_add_epi8(counter, nonzero);
+        *register = _mm_sub_epi8(*register, nonzero);
+    }
+    *cache_line = CacheLineUnion { sse: sse2 }.arr;
+    counter = _mm_add_epi8(counter, _mm_srli_epi16::<4>(counter));
+    counter = _mm_and_si128(counter, _mm_set1_epi8(0x0F));
+    counter = _mm_sad_epu8(counter, _mm_setzero_si128());
+    (_mm_cvtsi128_si32(counter) + _mm_extract_epi16::<4>(counter)) as _
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn decrement_avx2(cache_line: &mut CacheLine) -> u8 {
+    let mut avx2 = CacheLineUnion { arr: *cache_line }.avx2;
+    let mut counter = _mm256_setzero_si256();
+    for register in avx2.iter_mut() {
+        let mut nonzero = _mm256_or_si256(*register, _mm256_srli_epi16::<2>(*register));
+        nonzero = _mm256_or_si256(nonzero, _mm256_srli_epi16::<1>(nonzero));
+        nonzero = _mm256_and_si256(nonzero, _mm256_set1_epi8(0x11));
+        counter = _mm256_add_epi8(counter, nonzero);
+        *register = _mm256_sub_epi8(*register, nonzero);
+    }
+    *cache_line = CacheLineUnion { avx2 }.arr;
+    counter = _mm256_add_epi8(counter, _mm256_srli_epi16::<4>(counter));
+    counter = _mm256_and_si256(counter, _mm256_set1_epi8(0x0F));
+    counter = _mm256_sad_epu8(counter, _mm256_setzero_si256());
+    let lo = _mm256_castsi256_si128(counter);
+    let hi = _mm256_extracti128_si256::<1>(counter);
+    let added = _mm_add_epi64(lo, hi);
+    let unpacked = _mm_unpackhi_epi64(added, added);
+    _mm_cvtsi128_si64(_mm_add_epi64(added, unpacked)) as _
+}
+
+#[cfg(feature = "stdavx512")]
+#[target_feature(enable = "avx512f,avx512bw")]
+pub(super) unsafe fn decrement_avx512(cache_line: &mut CacheLine) -> u8 {
+    let register = CacheLineUnion { arr: *cache_line }.avx512;
+    let mut nonzero = _mm512_or_si512(register, _mm512_srli_epi16::<2>(register));
+    nonzero = _mm512_or_si512(nonzero, _mm512_srli_epi16::<1>(nonzero));
+    nonzero = _mm512_and_si512(nonzero, _mm512_set1_epi8(0x11));
+    let avx512 = _mm512_sub_epi8(register, nonzero);
+    *cache_line = CacheLineUnion { avx512 }.arr;
+    nonzero = _mm512_add_epi8(nonzero, _mm512_srli_epi16::<4>(nonzero));
+    nonzero = _mm512_and_si512(nonzero, _mm512_set1_epi8(0x0F));
+    nonzero = _mm512_sad_epu8(nonzero, _mm512_setzero_si512());
+    _mm512_reduce_add_epi64(nonzero) as _
+}
+
+pub(super) unsafe fn merge_sse2(cache_line: &mut CacheLine, other: &CacheLine) {
+    let mut sse2 = CacheLineUnion { arr: *cache_line }.sse;
+    let other = CacheLineUnion { arr: *other }.sse;
+    let nibbles = _mm_set1_epi8(0x0F);
+    for (register, other) in sse2.iter_mut().zip(other.iter()) {
+        let lo = _mm_add_epi8(
+            _mm_and_si128(*register, nibbles),
+            _mm_and_si128(*other, nibbles),
+        );
+        let hi = _mm_add_epi8(
+            _mm_and_si128(_mm_srli_epi16::<4>(*register), nibbles),
+            _mm_and_si128(_mm_srli_epi16::<4>(*other), nibbles),
+        );
+        let (lo, hi) = (_mm_min_epu8(lo, nibbles), _mm_min_epu8(hi, nibbles));
+        *register = _mm_or_si128(lo, _mm_slli_epi16::<4>(hi));
+    }
+    *cache_line = CacheLineUnion { sse: sse2 }.arr;
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn merge_avx2(cache_line: &mut CacheLine, other: &CacheLine) {
+    let mut avx2 = CacheLineUnion { arr: *cache_line }.avx2;
+    let other = CacheLineUnion { arr: *other }.avx2;
+    let nibbles = _mm256_set1_epi8(0x0F);
+    for (register, other) in avx2.iter_mut().zip(other.iter()) {
+        let lo = _mm256_add_epi8(
+            _mm256_and_si256(*register, nibbles),
+            _mm256_and_si256(*other, nibbles),
+        );
+        let hi = _mm256_add_epi8(
+            _mm256_and_si256(_mm256_srli_epi16::<4>(*register), nibbles),
+            _mm256_and_si256(_mm256_srli_epi16::<4>(*other), nibbles),
+        );
+        let (lo, hi) = (_mm256_min_epu8(lo, nibbles), _mm256_min_epu8(hi, nibbles));
+        *register = _mm256_or_si256(lo, _mm256_slli_epi16::<4>(hi));
+    }
+    *cache_line = CacheLineUnion { avx2 }.arr;
+}
+
+#[cfg(feature = "stdavx512")]
+#[target_feature(enable = "avx512f,avx512bw")]
+pub(super) unsafe fn merge_avx512(cache_line: &mut CacheLine, other: &CacheLine) {
+    let register = CacheLineUnion { arr: *cache_line }.avx512;
+    let other = CacheLineUnion { arr: *other }.avx512;
+    let nibbles = _mm512_set1_epi8(0x0F);
+    let lo = _mm512_add_epi8(
+        _mm512_and_si512(register, nibbles),
+        _mm512_and_si512(other, nibbles),
+    );
+    let hi = _mm512_add_epi8(
+        _mm512_and_si512(_mm512_srli_epi16::<4>(register), nibbles),
+        _mm512_and_si512(_mm512_srli_epi16::<4>(other), nibbles),
+    );
+    let (lo, hi) = (_mm512_min_epu8(lo, nibbles), _mm512_min_epu8(hi, nibbles));
+    let avx512 = _mm512_or_si512(lo, _mm512_slli_epi16::<4>(hi));
+    *cache_line = CacheLineUnion { avx512 }.arr;
+}
+
+#[target_feature(enable = "sse2")]
+pub(super) unsafe fn clear_sse2(cache_lines: &mut [CacheLine]) {
+    let zero = _mm_setzero_si128();
+    for cache_line in cache_lines {
+        let registers = cache_line as *mut CacheLine as *mut __m128i;
+        for i in 0..4 {
+            _mm_store_si128(registers.add(i), zero);
         }
     }
 }
 
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn clear_avx2(cache_lines: &mut [CacheLine]) {
+    let zero = _mm256_setzero_si256();
+    for cache_line in cache_lines {
+        let registers = cache_line as *mut CacheLine as *mut __m256i;
+        _mm256_store_si256(registers, zero);
+        _mm256_store_si256(registers.add(1), zero);
+    }
+}
+
+#[cfg(feature = "stdavx512")]
+#[target_feature(enable = "avx512f")]
+pub(super) unsafe fn clear_avx512(cache_lines: &mut [CacheLine]) {
+    let zero = _mm512_setzero_si512();
+    for cache_line in cache_lines {
+        _mm512_store_si512(cache_line as *mut CacheLine as *mut __m512i, zero);
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::super::tests::{
+        check_clear, check_conservative_increment, check_contains, check_decrement, check_merge,
+        check_reset, check_sat_inc_and_min,
+    };
     use super::super::*;
     use super::*;
-    use rand::Rng;
     use std::collections::HashSet;
 
     fn deinterleave(x: u16, y: u16) -> [u64; 2] {
@@ -152,6 +320,7 @@ mod tests {
             assert_eq!(y_1 & MASK, 0);
             assert_eq!(x_1.count_ones(), 4);
             assert_eq!(y_1.count_ones(), 4);
+            assert_eq!([x_1, y_1], portable::mask_deinterleave(x, y));
             assert!(set.insert(x_1) && set.insert(y_1));
         }
         assert_eq!(set.len(), UNPACKED.len())
@@ -159,52 +328,52 @@ mod tests {
 
     #[test]
     fn test_sat_inc_and_min() {
-        fn simple_min(x: u64, mask: u64) -> u8 {
-            use std::array::IntoIter;
-            let masked = x | !(mask * 0xF);
-            let hi = IntoIter::new(masked.to_le_bytes()).min().unwrap();
-            let lo = IntoIter::new((masked << 4).to_le_bytes()).min().unwrap();
-            hi.min(lo) >> 4
-        }
-        const fn simple_inc(x: u64, mask: u64) -> u64 {
-            let sat = x & (x >> 2);
-            x + (!(sat & (sat >> 1)) & mask)
+        check_sat_inc_and_min(Backend::Sse41.kernels());
+    }
+
+    #[test]
+    fn test_conservative_increment() {
+        check_conservative_increment(Backend::Sse41.kernels());
+    }
+
+    #[test]
+    fn test_contains() {
+        check_contains(Backend::Sse41.kernels());
+    }
+
+    #[test]
+    fn test_reset() {
+        for backend in [Backend::Sse41, Backend::Avx2, Backend::Avx512] {
+            if backend.is_supported() {
+                check_reset(backend.kernels());
+            }
         }
-        let mut rng = rand::thread_rng();
-        for _ in 0..(1 << 22) {
-            let (nums, masks): ((u64, u64), (u16, u16)) = (rng.gen(), rng.gen());
-            let mut nums_mut = nums;
-            let (min_simd, sat) = increment((&mut nums_mut.0, &mut nums_mut.1), masks);
-            let min_simd = min_simd + sat as u8;
-            assert_eq!(min_simd, frequency(nums, masks) + 1);
-            let [mask_1, mask_2] = deinterleave(masks.0, masks.1);
-            let min_simple = simple_min(nums.0, mask_1).min(simple_min(nums.1, mask_2));
-            assert_eq!(min_simd, min_simple + 1);
-            assert_eq!(
-                nums_mut,
-                (simple_inc(nums.0, mask_1), simple_inc(nums.1, mask_2))
-            );
+    }
+
+    #[test]
+    fn test_decrement() {
+        for backend in [Backend::Sse41, Backend::Avx2, Backend::Avx512] {
+            if backend.is_supported() {
+                check_decrement(backend.kernels());
+            }
         }
     }
 
     #[test]
-    fn test_reset() {
-        fn reset(cache_line: &mut CacheLine) -> u8 {
-            let mut count = 0;
-            for x in cache_line.0.iter_mut() {
-                count += (*x & 0x1111_1111_1111_1111).count_ones();
-                *x = (*x >> 1) & 0x7777_7777_7777_7777;
+    fn test_merge() {
+        for backend in [Backend::Sse41, Backend::Avx2, Backend::Avx512] {
+            if backend.is_supported() {
+                check_merge(backend.kernels());
             }
-            count as _
         }
-        let mut rng = rand::thread_rng();
-        for _ in 0..(1 << 22) {
-            let mut cache_line = CacheLine(rng.gen());
-            let mut cloned = cache_line;
-            let count = unsafe { reset_sse2(&mut cache_line) };
-            let count_simple = reset(&mut cloned);
-            assert_eq!(count, count_simple);
-            assert_eq!(cache_line.0, cloned.0);
+    }
+
+    #[test]
+    fn test_clear() {
+        for backend in [Backend::Sse41, Backend::Avx2, Backend::Avx512] {
+            if backend.is_supported() {
+                check_clear(backend.kernels());
+            }
         }
     }
 }
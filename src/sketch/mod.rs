@@ -1,9 +1,40 @@
 use ahash::RandomState;
-use std::alloc::{Allocator, Global};
+use allocator_api2::alloc::{Allocator, Global};
+use allocator_api2::boxed::Box;
+use std::boxed::Box as StdBox;
+use std::convert::TryFrom;
+use std::fmt;
 use std::hash::{BuildHasher, Hash};
+use std::thread;
 
+mod aging;
+mod backend;
+mod bloom;
+mod builder;
+mod concurrent;
+mod error;
+mod incremental;
 #[cfg(target_arch = "x86_64")]
 mod intrinsics;
+mod portable;
+mod recorder;
+mod sharded;
+mod snapshot;
+mod view;
+
+pub use aging::{
+    AgingPolicy, CloneAgingPolicy, Decay, ManualAging, SampleHalving, SubtractOneDecay, TimeHalving,
+};
+pub use backend::Backend;
+use backend::Kernels;
+pub use bloom::BlockedBloomFilter;
+pub use builder::FrequencySketchBuilder;
+pub use concurrent::ConcurrentFrequencySketch;
+pub use error::SketchError;
+use incremental::IncrementalReset;
+pub use recorder::{OverflowPolicy, SketchRecorder};
+pub use sharded::ShardedFrequencySketch;
+pub use view::FrequencySketchRef;
 
 macro_rules! cfn_assert {
     ($x:expr $(,)*) => {{
@@ -12,6 +43,12 @@ macro_rules! cfn_assert {
     }};
 }
 
+const BATCH_SIZE: usize = 16;
+const PARALLEL_RESET_MIN_LINES: usize = 1 << 14;
+const KEYS_PER_LINE: usize = 8;
+/// Counters per line a key's counter collides with, seen as the width of one count-min row.
+const COUNTERS_PER_ROW: f64 = 16.0;
+const ROWS: i32 = 8;
 const BINOMIAL_8_2: usize = 28;
 const BINOMIAL_16_4: usize = 1_820;
 const ROT16_LEN: usize = 112;
@@ -96,10 +133,6 @@ const fn packed_4_bits_set() -> [u16; PACKED_LEN] {
     }
 }
 
-fn fast_range(hash: u32, range: u32) -> u32 {
-    ((hash as u64 * range as u64) >> 32) as u32
-}
-
 fn index_and_rotation(idx: u32) -> (u32, u32) {
     const ROT16_END: i32 = ROT16_LEN as i32 * 16;
     let rot4 = idx as i32 - ROT16_END;
@@ -110,123 +143,946 @@ fn index_and_rotation(idx: u32) -> (u32, u32) {
     }
 }
 
-fn rotate_hash(hash: &mut u64, rotation: u32) -> u32 {
-    let h = *hash as u32;
-    *hash = hash.rotate_left(rotation);
-    h
+/// Maps `hash`, read as a fraction of 2^64, to `0..range`, leaving the fractional part of the
+/// product in `hash` for the next reduction.
+///
+/// The fractional part is independent of the returned value, so chaining reductions selects the
+/// blocks and the counters from disjoint hash bits, consuming `log2(range)` bits each.
+fn reduce(hash: &mut u64, range: u64) -> u64 {
+    let product = *hash as u128 * range as u128;
+    *hash = product as u64;
+    (product >> 64) as u64
 }
 
-fn four_bits_set_h(hash: u32) -> u16 {
-    let (idx, rot) = index_and_rotation(fast_range(hash, BINOMIAL_16_4 as u32));
+fn four_bits_set_h(hash: &mut u64) -> u16 {
+    let (idx, rot) = index_and_rotation(reduce(hash, BINOMIAL_16_4 as u64) as u32);
     unsafe { PACKED.get_unchecked(idx as usize).rotate_left(rot) }
 }
 
-fn block_indices_h(hash: u32) -> (usize, usize) {
-    let (idx_1, idx_2) = BLOCKS[fast_range(hash, BINOMIAL_8_2 as u32) as usize];
+fn block_indices_h(hash: &mut u64) -> (usize, usize) {
+    let (idx_1, idx_2) = BLOCKS[reduce(hash, BINOMIAL_8_2 as u64) as usize];
     (idx_1 as usize, idx_2 as usize)
 }
 
 fn block_masks(hash: &mut u64) -> (u16, u16) {
-    let (hash_1, hash_2) = (rotate_hash(hash, 12), rotate_hash(hash, 12));
-    (four_bits_set_h(hash_1), four_bits_set_h(hash_2))
+    (four_bits_set_h(hash), four_bits_set_h(hash))
 }
 
 #[repr(C, align(64))]
 #[derive(Default, Clone, Copy)]
 struct CacheLine([u64; 8]);
 
+unsafe impl bytemuck::Zeroable for CacheLine {}
+unsafe impl bytemuck::Pod for CacheLine {}
+
 impl CacheLine {
-    fn index_h(&self, hash: u32) -> (u64, u64) {
+    fn index_h(&self, hash: &mut u64) -> (u64, u64) {
         let (idx_1, idx_2) = block_indices_h(hash);
         unsafe { (*self.0.get_unchecked(idx_1), *self.0.get_unchecked(idx_2)) }
     }
 
-    fn index_mut_h(&mut self, hash: u32) -> (&mut u64, &mut u64) {
+    fn index_mut_h(&mut self, hash: &mut u64) -> (&mut u64, &mut u64) {
         let (idx_1, idx_2) = block_indices_h(hash);
         unsafe {
-            let block_1 = &mut *(self.0.get_unchecked_mut(idx_1 as usize) as *mut _);
-            let block_2 = &mut *(self.0.get_unchecked_mut(idx_2 as usize) as *mut _);
+            let block_1 = &mut *(self.0.get_unchecked_mut(idx_1) as *mut _);
+            let block_2 = &mut *(self.0.get_unchecked_mut(idx_2) as *mut _);
             (block_1, block_2)
         }
     }
 
-    pub fn frequency(&self, hash: &mut u64) -> u8 {
-        intrinsics::frequency(self.index_h(rotate_hash(hash, 8)), block_masks(hash))
+    fn frequency(&self, hash: &mut u64, kernels: &Kernels) -> u8 {
+        kernels.frequency(self.index_h(hash), block_masks(hash))
+    }
+
+    fn increment(&mut self, hash: &mut u64, kernels: &Kernels, mode: UpdateMode) -> (u8, bool) {
+        let blocks = self.index_mut_h(hash);
+        match mode {
+            UpdateMode::Standard => kernels.increment(blocks, block_masks(hash)),
+            UpdateMode::Conservative => kernels.conservative_increment(blocks, block_masks(hash)),
+        }
+    }
+
+    fn contains(&self, hash: &mut u64, kernels: &Kernels) -> bool {
+        let blocks = self.index_h(hash);
+        let masks = block_masks(hash);
+        kernels.contains(blocks, masks, bloom::bit_offset(hash))
     }
 
-    pub fn increment(&mut self, hash: &mut u64) -> (u8, bool) {
-        intrinsics::increment(self.index_mut_h(rotate_hash(hash, 8)), block_masks(hash))
+    fn insert(&mut self, hash: &mut u64) -> bool {
+        let (x, y) = self.index_mut_h(hash);
+        let (x_mask, y_mask) = block_masks(hash);
+        let offset = bloom::bit_offset(hash);
+        let [x_mask, y_mask] = portable::mask_deinterleave(x_mask, y_mask);
+        let (x_mask, y_mask) = (x_mask << offset, y_mask << offset);
+        let present = *x & x_mask == x_mask && *y & y_mask == y_mask;
+        *x |= x_mask;
+        *y |= y_mask;
+        !present
     }
 }
 
+/// Which of a key's 8 counters an increment bumps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum UpdateMode {
+    /// Increments all 8 counters.
+    #[default]
+    Standard,
+    /// Conservative update: increments only the counters equal to the key's current estimate.
+    ///
+    /// Counters shared with hotter keys no longer grow with every colder key hashed onto them,
+    /// which noticeably reduces overestimation at the same memory.
+    Conservative,
+}
+
 pub struct FrequencySketch<S: BuildHasher = RandomState, A: Allocator = Global> {
     sketch: Box<[CacheLine], A>,
     size: usize,
     sample_size: usize,
     hash_builder: S,
+    kernels: Kernels,
+    update_mode: UpdateMode,
+    /// Boxed with the global allocator, not `A`.
+    aging: StdBox<dyn AgingPolicy>,
+    incremental: Option<IncrementalReset<A>>,
+    doorkeeper: Option<BlockedBloomFilter<(), A>>,
+}
+
+/// Remixes a key's hash for its doorkeeper, so that keys sharing counters don't also share
+/// doorkeeper bits.
+///
+/// The multiply spreads every hash bit into the high bits choosing the doorkeeper line, and the
+/// shift folds them back into the low bits choosing its blocks and bits.
+fn doorkeeper_hash(hash: u64) -> u64 {
+    let mixed = hash.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    mixed ^ mixed >> 32
 }
 
 fn make_hash<Q: Hash + ?Sized, S: BuildHasher>(hash_builder: &S, val: &Q) -> u64 {
-    use core::hash::Hasher;
-    let mut state = hash_builder.build_hasher();
-    val.hash(&mut state);
-    state.finish()
+    hash_builder.hash_one(val)
 }
 
-fn cache_line_index(hash: u32, len: usize) -> usize {
-    fast_range(hash, len as u32) as usize
+/// The most cache lines a sketch can have while the hash still leaves enough bits to spread keys
+/// evenly over the blocks and counters of each line.
+pub(crate) const MAX_LINES: u64 = 1 << 35;
+
+/// [`MAX_LINES`] as a `usize`, capped on targets whose address space is smaller.
+pub(crate) fn max_lines() -> usize {
+    usize::try_from(MAX_LINES).unwrap_or(usize::MAX)
+}
+
+/// Selects the cache line for `hash` from its most significant bits, then reverses `hash` so the
+/// reductions within the line consume its least significant bits first.
+///
+/// A key's position within its line thus doesn't depend on the number of lines, and its line in a
+/// sketch resized `k` times larger or smaller is its old line times or divided by `k`.
+fn cache_line_index(hash: &mut u64, len: usize) -> usize {
+    let index = ((*hash as u128 * len as u128) >> 64) as usize;
+    *hash = hash.reverse_bits();
+    index
+}
+
+/// Allocates `lines` zeroed cache lines, failing on an invalid count or allocation failure.
+fn try_zeroed_lines<T: bytemuck::Zeroable, A: Allocator>(
+    lines: usize,
+    alloc: A,
+) -> Result<Box<[T], A>, SketchError> {
+    if lines == 0 || lines as u64 > MAX_LINES {
+        return Err(SketchError::InvalidSize(lines));
+    }
+    match Box::try_new_zeroed_slice_in(lines, alloc) {
+        Ok(lines) => Ok(unsafe { lines.assume_init() }),
+        Err(_) => Err(SketchError::AllocationFailed {
+            bytes: lines.saturating_mul(std::mem::size_of::<T>()),
+        }),
+    }
+}
+
+/// Ages every line of `lines`, returning the sum of [`Decay::apply`].
+fn age_lines(lines: &mut [CacheLine], decay: Decay, kernels: &Kernels) -> usize {
+    lines
+        .iter_mut()
+        .map(|cache_line| decay.apply(kernels, cache_line) as usize)
+        .sum()
+}
+
+fn prefetch(cache_line: &CacheLine) {
+    cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            unsafe { _mm_prefetch::<_MM_HINT_T0>(cache_line as *const CacheLine as *const i8) }
+        } else {
+            let _ = cache_line;
+        }
+    }
+}
+
+fn lines_for_error_bounds(epsilon: f64, delta: f64) -> usize {
+    assert!(epsilon > 0.0 && epsilon <= 1.0, "0 < epsilon <= 1");
+    assert!(delta > 0.0 && delta < 1.0, "0 < delta < 1");
+    (delta.powf(-1.0 / ROWS as f64) / (COUNTERS_PER_ROW * epsilon)).ceil() as usize
 }
 
 impl FrequencySketch<RandomState, Global> {
     pub fn with_capacity(sketch_size: usize) -> Self {
         Self::with_capacity_and_hasher_in(sketch_size, RandomState::new(), Global)
     }
+
+    /// Like [`with_capacity`](Self::with_capacity), returning an error instead of panicking or
+    /// aborting.
+    pub fn try_with_capacity(sketch_size: usize) -> Result<Self, SketchError> {
+        Self::try_with_capacity_and_hasher_in(sketch_size, RandomState::new(), Global)
+    }
+
+    /// Creates a sketch sized for `expected_keys` distinct keys: 16 counters per key, and a
+    /// sample size of about 10 samples per key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `expected_keys` is zero or needs more than 2^35 cache lines.
+    pub fn for_entries(expected_keys: usize) -> Self {
+        Self::with_capacity(expected_keys.div_ceil(KEYS_PER_LINE))
+    }
+
+    /// Creates the smallest sketch whose estimates exceed the true frequency by more than
+    /// `epsilon` times the [`sample_size`](Self::sample_size) with probability at most `delta`.
+    ///
+    /// A key's 8 counters are treated as the rows of a count-min sketch, each as wide as the 16
+    /// counters per cache line it collides with, so `lines = ⌈δ^(-1/8) / 16ε⌉`. This ignores the
+    /// correlation between counters of the same line and is optimistic for heavily skewed keys.
+    ///
+    /// Only the line count follows from the bounds: the sample size stays at the default 80
+    /// samples per line, so `epsilon` bounds the over-estimate relative to the sample size, not in
+    /// absolute counts. `epsilon` times the sample size is `5δ^(-1/8)` for every `epsilon`, at least
+    /// 5 of the 15 a counter holds. A smaller absolute over-estimate takes a smaller sample size,
+    /// set through [`FrequencySketchBuilder::aging`].
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `0 < epsilon <= 1` and `0 < delta < 1`, or if the sketch would need more than
+    /// 2^35 cache lines.
+    pub fn with_error_bounds(epsilon: f64, delta: f64) -> Self {
+        Self::with_capacity(lines_for_error_bounds(epsilon, delta))
+    }
+
+    pub fn builder(sketch_size: usize) -> FrequencySketchBuilder {
+        FrequencySketchBuilder::new(sketch_size)
+    }
 }
 
 impl<S: BuildHasher, A: Allocator> FrequencySketch<S, A> {
+    /// # Panics
+    ///
+    /// Panics if `sketch_size` is zero or greater than 2^35, and aborts if the allocation
+    /// fails. See [`try_with_capacity_and_hasher_in`](Self::try_with_capacity_and_hasher_in).
     pub fn with_capacity_and_hasher_in(sketch_size: usize, hasher: S, alloc: A) -> Self {
-        assert!(
-            sketch_size > 0 && sketch_size <= u32::MAX as _,
-            "0 < sketch <= u32::MAX"
-        );
-        let sketch = unsafe { Box::new_zeroed_slice_in(sketch_size, alloc).assume_init() };
-        Self {
+        Self::try_with_capacity_and_hasher_in(sketch_size, hasher, alloc)
+            .unwrap_or_else(|err| err.raise())
+    }
+
+    /// Creates a sketch of `sketch_size` cache lines, failing with
+    /// [`SketchError::InvalidSize`] if it is zero or greater than 2^35, or with
+    /// [`SketchError::AllocationFailed`] if `alloc` can't provide the counters.
+    ///
+    /// The counters come from `alloc`, but the default aging policy is boxed with the global
+    /// allocator.
+    pub fn try_with_capacity_and_hasher_in(
+        sketch_size: usize,
+        hasher: S,
+        alloc: A,
+    ) -> Result<Self, SketchError> {
+        let sketch = try_zeroed_lines(sketch_size, alloc)?;
+        let aging = SampleHalving::default();
+        Ok(Self {
             sketch,
             size: 0,
-            sample_size: sketch_size * 80,
+            sample_size: aging.sample_size(sketch_size),
             hash_builder: hasher,
+            kernels: Backend::detect().kernels(),
+            update_mode: UpdateMode::Standard,
+            aging: StdBox::new(aging),
+            incremental: None,
+            doorkeeper: None,
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.kernels.backend()
+    }
+
+    /// Forces the sketch to use `backend` instead of the one detected at construction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the running CPU does not support `backend`.
+    pub fn force_backend(&mut self, backend: Backend) {
+        self.kernels = backend.kernels();
+        if let Some(doorkeeper) = &mut self.doorkeeper {
+            doorkeeper.force_backend(backend);
         }
     }
 
+    /// Returns the number of samples recorded since the last reset.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn sample_size(&self) -> usize {
+        self.sample_size
+    }
+
+    /// Returns the `epsilon` of [`with_error_bounds`](FrequencySketch::with_error_bounds) this
+    /// sketch meets for a `delta` of `e^-8`, about 0.03%.
+    ///
+    /// The error is relative to the [`sample_size`](Self::sample_size): estimates exceed the true
+    /// frequency by more than `estimated_error() * sample_size()` counts with probability at most
+    /// `delta`, which depends on the aging policy as much as on the number of lines.
+    pub fn estimated_error(&self) -> f64 {
+        std::f64::consts::E / (COUNTERS_PER_ROW * self.sketch.len() as f64)
+    }
+
+    /// Returns the heap memory used by the counters, the doorkeeper and incremental reset state.
+    pub fn memory_bytes(&self) -> usize {
+        let line = std::mem::size_of::<CacheLine>();
+        let doorkeeper = self.doorkeeper.as_ref().map_or(0, |d| d.lines.len() * line);
+        let incremental = self.incremental.as_ref().map_or(0, |i| i.memory_bytes());
+        self.sketch.len() * line + doorkeeper + incremental
+    }
+
+    pub fn update_mode(&self) -> UpdateMode {
+        self.update_mode
+    }
+
+    /// Returns the hasher used to turn keys into the hashes accepted by
+    /// [`frequency_hash`](Self::frequency_hash) and [`increment_hash`](Self::increment_hash).
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
     pub fn frequency<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
-        let hash = &mut make_hash(&self.hash_builder, key);
-        let index = cache_line_index(rotate_hash(hash, 32), self.sketch.len());
-        self.sketch[index].frequency(hash)
+        self.frequency_hash(make_hash(&self.hash_builder, key))
+    }
+
+    pub fn has_doorkeeper(&self) -> bool {
+        self.doorkeeper.is_some()
+    }
+
+    /// Like [`frequency`](Self::frequency), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn frequency_hash(&self, hash: u64) -> u8 {
+        let admitted = self
+            .doorkeeper
+            .as_ref()
+            .is_some_and(|d| d.contains_hash(doorkeeper_hash(hash)));
+        let hash = &mut { hash };
+        let index = cache_line_index(hash, self.sketch.len());
+        let frequency = self.sketch[index].frequency(hash, &self.kernels);
+        let frequency = match &self.incremental {
+            Some(incremental) => incremental.estimate(index, frequency),
+            None => frequency,
+        };
+        frequency + admitted as u8
     }
 
     pub fn increment<Q: Hash + ?Sized>(&mut self, key: &Q) -> u8 {
-        let hash = &mut make_hash(&self.hash_builder, key);
-        let index = cache_line_index(rotate_hash(hash, 32), self.sketch.len());
-        let (frequency, saturated) = self.sketch[index].increment(hash);
+        self.increment_hash(make_hash(&self.hash_builder, key))
+    }
+
+    /// Like [`increment`](Self::increment), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn increment_hash(&mut self, hash: u64) -> u8 {
+        let first_seen = match &mut self.doorkeeper {
+            Some(doorkeeper) => doorkeeper.insert_hash(doorkeeper_hash(hash)),
+            None => false,
+        };
+        let (frequency, saturated) = if first_seen {
+            (self.frequency_hash(hash), false)
+        } else {
+            let (frequency, saturated) = self.increment_counters(hash);
+            (frequency + self.doorkeeper.is_some() as u8, saturated)
+        };
         self.size += !saturated as usize;
+        if let Some(incremental) = &mut self.incremental {
+            let step = incremental.step();
+            let forgotten = incremental.advance(&mut self.sketch, &self.kernels, step);
+            self.size = self.size.saturating_sub(forgotten);
+        }
         if self.size >= self.sample_size {
-            self.reset();
+            self.age();
         }
         frequency
     }
 
+    fn age(&mut self) {
+        if self.aging.should_age() {
+            self.reset();
+        } else {
+            let sample_size = self.aging.sample_size(self.sketch.len());
+            self.sample_size = self.size.saturating_add(sample_size);
+        }
+    }
+
+    /// Replaces the aging policy, [`SampleHalving`] with the default factor unless set through
+    /// [`FrequencySketchBuilder::aging`].
+    ///
+    /// The policy is boxed with the global allocator, not the sketch's.
+    pub fn set_aging<P: AgingPolicy + 'static>(&mut self, aging: P) {
+        self.sample_size = aging.sample_size(self.sketch.len());
+        self.aging = StdBox::new(aging);
+    }
+
+    fn increment_counters(&mut self, mut hash: u64) -> (u8, bool) {
+        let hash = &mut hash;
+        let index = cache_line_index(hash, self.sketch.len());
+        if let Some(incremental) = &mut self.incremental {
+            let forgotten = incremental.age_line(index, &mut self.sketch[index], &self.kernels);
+            self.size = self.size.saturating_sub(forgotten);
+        }
+        self.sketch[index].increment(hash, &self.kernels, self.update_mode)
+    }
+
+    /// Estimates the frequency of every key in `keys` into `frequencies`.
+    ///
+    /// Keys are processed in batches: every key of a batch is hashed and its cache line
+    /// prefetched before any counters are read, so the cache misses overlap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keys` and `frequencies` differ in length.
+    pub fn frequency_many<Q: Hash>(&self, keys: &[Q], frequencies: &mut [u8]) {
+        assert_eq!(
+            keys.len(),
+            frequencies.len(),
+            "keys.len() == frequencies.len()"
+        );
+        let mut hashes = [0; BATCH_SIZE];
+        let batches = keys
+            .chunks(BATCH_SIZE)
+            .zip(frequencies.chunks_mut(BATCH_SIZE));
+        for (keys, frequencies) in batches {
+            let hashes = &mut hashes[..keys.len()];
+            self.hash_and_prefetch(keys, hashes);
+            for (frequency, &hash) in frequencies.iter_mut().zip(hashes.iter()) {
+                *frequency = self.frequency_hash(hash);
+            }
+        }
+    }
+
+    /// Like [`frequency_many`](Self::frequency_many), for keys already hashed with
+    /// [`hasher`](Self::hasher).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hashes` and `frequencies` differ in length.
+    pub fn frequency_many_hash(&self, hashes: &[u64], frequencies: &mut [u8]) {
+        assert_eq!(
+            hashes.len(),
+            frequencies.len(),
+            "hashes.len() == frequencies.len()"
+        );
+        let batches = hashes
+            .chunks(BATCH_SIZE)
+            .zip(frequencies.chunks_mut(BATCH_SIZE));
+        for (hashes, frequencies) in batches {
+            hashes.iter().for_each(|&hash| self.prefetch_hash(hash));
+            for (frequency, &hash) in frequencies.iter_mut().zip(hashes.iter()) {
+                *frequency = self.frequency_hash(hash);
+            }
+        }
+    }
+
+    /// Increments every key in `keys`, with the same result as calling
+    /// [`increment`](Self::increment) on each of them in order.
+    ///
+    /// Keys are processed in batches: every key of a batch is hashed and its cache line
+    /// prefetched before any counters are touched, so the cache misses overlap.
+    pub fn increment_many<Q: Hash>(&mut self, keys: &[Q]) {
+        let mut hashes = [0; BATCH_SIZE];
+        for keys in keys.chunks(BATCH_SIZE) {
+            let hashes = &mut hashes[..keys.len()];
+            self.hash_and_prefetch(keys, hashes);
+            for &hash in hashes.iter() {
+                self.increment_hash(hash);
+            }
+        }
+    }
+
+    /// Like [`increment_many`](Self::increment_many), for keys already hashed with
+    /// [`hasher`](Self::hasher).
+    pub fn increment_many_hash(&mut self, hashes: &[u64]) {
+        for hashes in hashes.chunks(BATCH_SIZE) {
+            hashes.iter().for_each(|&hash| self.prefetch_hash(hash));
+            for &hash in hashes {
+                self.increment_hash(hash);
+            }
+        }
+    }
+
+    fn hash_and_prefetch<Q: Hash>(&self, keys: &[Q], hashes: &mut [u64]) {
+        for (hash, key) in hashes.iter_mut().zip(keys) {
+            *hash = make_hash(&self.hash_builder, key);
+            self.prefetch_hash(*hash);
+        }
+    }
+
+    /// Hints the CPU to start loading the cache line of `key` without reading it, so a later
+    /// [`frequency`](Self::frequency) or [`increment`](Self::increment) doesn't stall on the
+    /// miss. A no-op on targets without a stable prefetch intrinsic.
+    pub fn prefetch<Q: Hash + ?Sized>(&self, key: &Q) {
+        self.prefetch_hash(make_hash(&self.hash_builder, key));
+    }
+
+    /// Like [`prefetch`](Self::prefetch), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn prefetch_hash(&self, hash: u64) {
+        prefetch(&self.sketch[cache_line_index(&mut { hash }, self.sketch.len())]);
+        if let Some(doorkeeper) = &self.doorkeeper {
+            doorkeeper.prefetch_hash(doorkeeper_hash(hash));
+        }
+    }
+
+    /// Ages the counters with the [`Decay`] of the aging policy and clears the doorkeeper.
+    ///
+    /// With an [incremental reset](FrequencySketchBuilder::incremental_reset) the counters are
+    /// only aged over the following increments, and an unfinished previous reset is finished first.
     pub fn reset(&mut self) {
+        let decay = self.aging.decay();
+        if let Some(incremental) = &mut self.incremental {
+            let forgotten = incremental.advance(&mut self.sketch, &self.kernels, usize::MAX);
+            incremental.start(decay);
+            self.size = decay.start_size(self.size.saturating_sub(forgotten));
+        } else {
+            let count = age_lines(&mut self.sketch, decay, &self.kernels);
+            self.size = decay
+                .start_size(self.size)
+                .saturating_sub(decay.forgotten(count));
+        }
+        self.reset_sample_count();
+    }
+
+    /// Like [`reset`](Self::reset), with the counters split into chunks aged on every available
+    /// core, for the same counters and sample count as the serial reset.
+    ///
+    /// The whole sketch is aged before returning, even with an
+    /// [incremental reset](FrequencySketchBuilder::incremental_reset). Sketches too small to be
+    /// worth the threads are aged on the calling thread.
+    pub fn reset_parallel(&mut self) {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk = self.sketch.len().div_ceil(threads);
+        self.reset_in_chunks(chunk.max(PARALLEL_RESET_MIN_LINES));
+    }
+
+    fn reset_in_chunks(&mut self, chunk: usize) {
+        let decay = self.aging.decay();
+        self.finish_reset();
+        let (sketch, kernels) = (&mut self.sketch, &self.kernels);
+        let count = thread::scope(|s| {
+            let mut chunks = sketch.chunks_mut(chunk);
+            let first = chunks.next().unwrap();
+            let handles: Vec<_> = chunks
+                .map(|lines| s.spawn(move || age_lines(lines, decay, kernels)))
+                .collect();
+            let count = age_lines(first, decay, kernels);
+            count
+                + handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .sum::<usize>()
+        });
+        self.size = decay
+            .start_size(self.size)
+            .saturating_sub(decay.forgotten(count));
+        self.reset_sample_count();
+    }
+
+    fn reset_sample_count(&mut self) {
+        self.sample_size = self.aging.sample_size(self.sketch.len());
+        if let Some(doorkeeper) = &mut self.doorkeeper {
+            doorkeeper.clear();
+        }
+    }
+
+    /// Returns whether an incremental reset is still aging the counters.
+    pub fn is_resetting(&self) -> bool {
+        self.incremental.as_ref().is_some_and(|i| i.is_pending())
+    }
+
+    /// Ages every line an unfinished incremental reset hasn't reached yet.
+    pub fn finish_reset(&mut self) {
+        if let Some(incremental) = &mut self.incremental {
+            let forgotten = incremental.advance(&mut self.sketch, &self.kernels, usize::MAX);
+            self.size = self.size.saturating_sub(forgotten);
+        }
+    }
+
+    /// Returns every cache line as it is once any unfinished reset finishes, and [`Decay::apply`]
+    /// for it, aging a copy of one line at a time rather than of the whole sketch.
+    fn aged_lines(&self) -> impl Iterator<Item = (CacheLine, usize)> + '_ {
+        let incremental = self.incremental.as_ref().filter(|i| i.is_pending());
+        let lines = self.sketch.iter().enumerate();
+        lines.map(move |(index, cache_line)| match incremental {
+            Some(incremental) => incremental.aged_line(index, cache_line, &self.kernels),
+            None => (*cache_line, 0),
+        })
+    }
+
+    /// Returns the sample count once any unfinished reset finishes, from the sum of
+    /// [`aged_lines`](Self::aged_lines).
+    fn aged_size(&self, count: usize) -> usize {
+        match &self.incremental {
+            Some(incremental) if incremental.is_pending() => {
+                self.size.saturating_sub(incremental.forgotten(count))
+            }
+            _ => self.size,
+        }
+    }
+
+    /// Adds the counters of `other` into this sketch, saturating each at 15, and unions the
+    /// doorkeepers, so no estimate ends up below its estimate in either input.
+    ///
+    /// The sample count becomes the sum of both, and a merged sketch past its sample size halves
+    /// on its next increment. Merging is associative and commutative.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the sketches differ in size, hasher seed or doorkeeper size.
+    pub fn merge(&mut self, other: &Self) {
+        assert_eq!(self.sketch.len(), other.sketch.len(), "sketch sizes differ");
+        assert_eq!(
+            snapshot::fingerprint(&self.hash_builder),
+            snapshot::fingerprint(&other.hash_builder),
+            "sketch hashers differ"
+        );
+        match (&mut self.doorkeeper, &other.doorkeeper) {
+            (Some(doorkeeper), Some(other)) => doorkeeper.union(other),
+            (None, None) => {}
+            _ => panic!("only one sketch has a doorkeeper"),
+        }
+        self.finish_reset();
         let mut count = 0;
-        for cache_line in self.sketch.iter_mut() {
-            count += intrinsics::reset(cache_line) as usize;
+        for (cache_line, (other_line, other_count)) in
+            self.sketch.iter_mut().zip(other.aged_lines())
+        {
+            self.kernels.merge(cache_line, &other_line);
+            count += other_count;
+        }
+        self.size = self.size.saturating_add(other.aged_size(count));
+    }
+
+    /// Zeroes every counter and the doorkeeper and drops any unfinished reset, leaving the sketch
+    /// as if newly built with the same configuration.
+    pub fn clear(&mut self) {
+        self.kernels.clear(&mut self.sketch);
+        if let Some(incremental) = &mut self.incremental {
+            incremental.clear();
         }
-        self.size = (self.size >> 1) - (count >> 2);
+        self.size = 0;
+        self.reset_sample_count();
+    }
+}
+
+impl<S: BuildHasher + Clone, A: Allocator + Clone> Clone for FrequencySketch<S, A> {
+    fn clone(&self) -> Self {
+        Self {
+            sketch: self.sketch.clone(),
+            size: self.size,
+            sample_size: self.sample_size,
+            hash_builder: self.hash_builder.clone(),
+            kernels: self.kernels,
+            update_mode: self.update_mode,
+            aging: self.aging.clone_box(),
+            incremental: self.incremental.clone(),
+            doorkeeper: self.doorkeeper.clone(),
+        }
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> fmt::Debug for FrequencySketch<S, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrequencySketch")
+            .field("lines", &self.sketch.len())
+            .field("size", &self.size)
+            .field("sample_size", &self.sample_size)
+            .field("backend", &self.backend())
+            .field("update_mode", &self.update_mode)
+            .field("decay", &self.aging.decay())
+            .field("resetting", &self.is_resetting())
+            .field(
+                "doorkeeper",
+                &self.doorkeeper.as_ref().map(|d| d.lines.len()),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sketches are equal when they hash keys alike and hold the same counters, doorkeeper bits, sample
+/// count and sample size, whatever their backend or aging policy. Counters and sample count are
+/// compared as they are once any unfinished reset finishes.
+impl<S: BuildHasher, A: Allocator> PartialEq for FrequencySketch<S, A> {
+    fn eq(&self, other: &Self) -> bool {
+        fn words(lines: &[CacheLine]) -> &[u64] {
+            bytemuck::cast_slice(lines)
+        }
+        let doorkeepers = match (&self.doorkeeper, &other.doorkeeper) {
+            (Some(a), Some(b)) => a.len == b.len && words(&a.lines) == words(&b.lines),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        let same = self.sketch.len() == other.sketch.len()
+            && self.sample_size == other.sample_size
+            && self.update_mode == other.update_mode
+            && snapshot::fingerprint(&self.hash_builder)
+                == snapshot::fingerprint(&other.hash_builder)
+            && doorkeepers;
+        if !same {
+            return false;
+        }
+        let (mut count, mut other_count) = (0, 0);
+        let mut lines = self.aged_lines().zip(other.aged_lines());
+        let same_lines = lines.all(|((line, line_count), (other_line, other_line_count))| {
+            count += line_count;
+            other_count += other_line_count;
+            line.0 == other_line.0
+        });
+        same_lines && self.aged_size(count) == other.aged_size(other_count)
+    }
+}
+
+impl<S: BuildHasher, A: Allocator + Clone> FrequencySketch<S, A> {
+    /// Resizes the sketch to `lines` cache lines, keeping the counters.
+    ///
+    /// Every new line takes the counter-wise maximum of the old lines it overlaps, so no estimate
+    /// drops. Shrinking by a factor `k` folds each `k` consecutive lines into one, raising the
+    /// estimates of keys sharing counters with hotter keys of the other lines. Growing by a factor
+    /// `k` copies each line into `k` lines, which keeps every estimate but also the collisions of
+    /// the old size until the counters age. Other sizes combine both.
+    ///
+    /// An unfinished reset is finished first. The doorkeeper and sample count are kept, and the
+    /// sample size follows the new number of lines.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `lines` is zero or greater than 2^35, and aborts if the allocation fails. See
+    /// [`try_resize`](Self::try_resize).
+    pub fn resize(&mut self, lines: usize) {
+        self.try_resize(lines).unwrap_or_else(|err| err.raise())
+    }
+
+    /// Like [`resize`](Self::resize), returning an error and leaving the sketch unchanged instead
+    /// of panicking or aborting.
+    pub fn try_resize(&mut self, lines: usize) -> Result<(), SketchError> {
+        let alloc = Box::allocator(&self.sketch);
+        let mut resized = try_zeroed_lines(lines, alloc.clone())?;
+        let incremental = self
+            .incremental
+            .as_ref()
+            .map(|incremental| IncrementalReset::try_new(lines, incremental.step(), alloc.clone()));
+        let incremental = incremental.transpose()?;
+        self.finish_reset();
+        let (old, new) = (self.sketch.len() as u128, lines as u128);
+        for (index, cache_line) in resized.iter_mut().enumerate() {
+            let index = index as u128;
+            let start = (index * old / new) as usize;
+            let end = ((index + 1) * old).div_ceil(new) as usize;
+            for other in &self.sketch[start..end] {
+                portable::max(cache_line, other);
+            }
+        }
+        self.sketch = resized;
+        self.incremental = incremental;
+        self.sample_size = self.aging.sample_size(lines);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use allocator_api2::alloc::AllocError;
+    use rand::Rng;
+    use std::alloc::Layout;
+    use std::ptr::NonNull;
+
+    /// Returns the words of every cache line of `sketch`.
+    pub(super) fn lines(sketch: &FrequencySketch) -> Vec<[u64; 8]> {
+        sketch
+            .sketch
+            .iter()
+            .map(|cache_line| cache_line.0)
+            .collect()
+    }
+
+    /// Starts a builder for a sketch of `lines` cache lines with a fixed hasher seed.
+    pub(super) fn seeded(lines: usize) -> FrequencySketchBuilder {
+        FrequencySketch::builder(lines).hasher(RandomState::with_seeds(1, 2, 3, 4))
+    }
+
+    /// An allocator that always fails.
+    #[derive(Clone)]
+    pub(super) struct Failing;
+
+    unsafe impl Allocator for Failing {
+        fn allocate(&self, _: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            Err(AllocError)
+        }
+
+        unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {}
+    }
+
+    pub(super) fn check_sat_inc_and_min(kernels: Kernels) {
+        fn simple_min(x: u64, mask: u64) -> u8 {
+            let masked = x | !(mask * 0xF);
+            let hi = IntoIterator::into_iter(masked.to_le_bytes()).min().unwrap();
+            let lo = IntoIterator::into_iter((masked << 4).to_le_bytes())
+                .min()
+                .unwrap();
+            hi.min(lo) >> 4
+        }
+        const fn simple_inc(x: u64, mask: u64) -> u64 {
+            let sat = x & (x >> 2);
+            x + (!(sat & (sat >> 1)) & mask)
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 22) {
+            let (nums, masks): ((u64, u64), (u16, u16)) = (rng.gen(), rng.gen());
+            let mut nums_mut = nums;
+            let (min_simd, sat) = kernels.increment((&mut nums_mut.0, &mut nums_mut.1), masks);
+            let min_simd = min_simd + sat as u8;
+            assert_eq!(min_simd, kernels.frequency(nums, masks) + 1);
+            let [mask_1, mask_2] = portable::mask_deinterleave(masks.0, masks.1);
+            let min_simple = simple_min(nums.0, mask_1).min(simple_min(nums.1, mask_2));
+            assert_eq!(min_simd, min_simple + 1);
+            assert_eq!(
+                nums_mut,
+                (simple_inc(nums.0, mask_1), simple_inc(nums.1, mask_2))
+            );
+        }
+    }
+
+    pub(super) fn check_conservative_increment(kernels: Kernels) {
+        fn simple_inc(x: u64, mask: u64, min: u8) -> u64 {
+            (0..64).step_by(4).fold(x, |x, shift| {
+                let selected = (mask >> shift) & 1 == 1;
+                let eq = (x >> shift) & 0xF == min as u64;
+                x + (((selected && eq && min < 0xF) as u64) << shift)
+            })
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 20) {
+            let (mut nums, masks): ((u64, u64), (u16, u16)) = (rng.gen(), rng.gen());
+            // Skew towards few distinct values so that ties and saturation are common.
+            let skew = rng.gen::<u64>() & rng.gen::<u64>() & 0xEEEE_EEEE_EEEE_EEEE;
+            nums.0 = if rng.gen() {
+                nums.0 | skew
+            } else {
+                nums.0 & !skew
+            };
+            let min = kernels.frequency(nums, masks);
+            let mut nums_mut = nums;
+            let (estimate, sat) =
+                kernels.conservative_increment((&mut nums_mut.0, &mut nums_mut.1), masks);
+            assert_eq!((estimate, sat), (min + (min < 0xF) as u8, min == 0xF));
+            let [mask_1, mask_2] = portable::mask_deinterleave(masks.0, masks.1);
+            assert_eq!(
+                nums_mut,
+                (
+                    simple_inc(nums.0, mask_1, min),
+                    simple_inc(nums.1, mask_2, min)
+                )
+            );
+        }
+    }
+
+    pub(super) fn check_contains(kernels: Kernels) {
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 20) {
+            let (masks, offset): ((u16, u16), u32) = (rng.gen(), rng.gen_range(0..4));
+            let [mask_1, mask_2] = portable::mask_deinterleave(masks.0, masks.1);
+            let (mask_1, mask_2) = (mask_1 << offset, mask_2 << offset);
+            let nums: (u64, u64) = (rng.gen::<u64>() | mask_1, rng.gen::<u64>() | mask_2);
+            assert!(kernels.contains(nums, masks, offset));
+            let missing = nums.0 & !(mask_1 & mask_1.wrapping_neg());
+            assert_eq!(
+                kernels.contains((missing, nums.1), masks, offset),
+                mask_1 == 0
+            );
+            let nums: (u64, u64) = rng.gen();
+            let simple = nums.0 & mask_1 == mask_1 && nums.1 & mask_2 == mask_2;
+            assert_eq!(kernels.contains(nums, masks, offset), simple);
+        }
+    }
+
+    pub(super) fn check_reset(kernels: Kernels) {
+        fn simple_reset(cache_line: &mut CacheLine) -> u8 {
+            let mut count = 0;
+            for x in cache_line.0.iter_mut() {
+                count += (*x & 0x1111_1111_1111_1111).count_ones();
+                *x = (*x >> 1) & 0x7777_7777_7777_7777;
+            }
+            count as _
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 22) {
+            let mut cache_line = CacheLine(rng.gen());
+            let mut cloned = cache_line;
+            let count = kernels.reset(&mut cache_line);
+            let count_simple = simple_reset(&mut cloned);
+            assert_eq!(count, count_simple);
+            assert_eq!(cache_line.0, cloned.0);
+        }
+    }
+
+    pub(super) fn check_decrement(kernels: Kernels) {
+        fn simple_decrement(cache_line: &mut CacheLine) -> u8 {
+            let mut count = 0;
+            for x in cache_line.0.iter_mut() {
+                for shift in (0..64).step_by(4) {
+                    if (*x >> shift) & 0xF != 0 {
+                        *x -= 1 << shift;
+                        count += 1;
+                    }
+                }
+            }
+            count
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 18) {
+            let mut cache_line = CacheLine(rng.gen());
+            // Clear about half the counters so that zeros are common.
+            for x in cache_line.0.iter_mut() {
+                *x &= (rng.gen::<u64>() & 0x1111_1111_1111_1111) * 0xF;
+            }
+            let mut cloned = cache_line;
+            assert_eq!(
+                kernels.decrement(&mut cache_line),
+                simple_decrement(&mut cloned)
+            );
+            assert_eq!(cache_line.0, cloned.0);
+        }
+    }
+
+    pub(super) fn check_merge(kernels: Kernels) {
+        fn simple_merge(x: u64, y: u64) -> u64 {
+            (0..64).step_by(4).fold(0, |merged, shift| {
+                let sum = ((x >> shift) & 0xF) + ((y >> shift) & 0xF);
+                merged | sum.min(0xF) << shift
+            })
+        }
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 18) {
+            let (mut cache_line, other) = (CacheLine(rng.gen()), CacheLine(rng.gen()));
+            let original = cache_line;
+            kernels.merge(&mut cache_line, &other);
+            for ((x, y), merged) in original.0.iter().zip(other.0).zip(cache_line.0) {
+                assert_eq!(merged, simple_merge(*x, y));
+            }
+        }
+    }
+
+    pub(super) fn check_clear(kernels: Kernels) {
+        let mut rng = rand::thread_rng();
+        for len in 0..16 {
+            let mut cache_lines: Vec<_> = (0..len).map(|_| CacheLine(rng.gen())).collect();
+            kernels.clear(&mut cache_lines);
+            assert!(cache_lines.iter().all(|cache_line| cache_line.0 == [0; 8]));
+        }
+    }
+
     #[test]
     fn test_packed_unpacked_eq() {
         let mut unpacked = [0; BINOMIAL_16_4];
@@ -237,4 +1093,360 @@ mod tests {
         unpacked.sort_unstable();
         assert_eq!(unpacked, UNPACKED);
     }
+
+    #[test]
+    fn test_hash_bits_independent() {
+        // More lines than u32::MAX, and not a power of two.
+        let len = 3 << 31;
+        let mut blocks = [[0usize; BINOMIAL_8_2]; 4];
+        let mut masks = [[0usize; 16]; 4];
+        let (mut rng, mut max_line) = (rand::thread_rng(), 0);
+        let samples = 1 << 20;
+        for _ in 0..samples {
+            let hash = &mut rng.gen::<u64>();
+            let line = cache_line_index(hash, len);
+            max_line = max_line.max(line);
+            let quarter = line * 4 / len;
+            blocks[quarter][reduce(hash, BINOMIAL_8_2 as u64) as usize] += 1;
+            masks[quarter][reduce(hash, BINOMIAL_16_4 as u64) as usize * 16 / BINOMIAL_16_4] += 1;
+        }
+        assert!(max_line > u32::MAX as usize && max_line < len);
+        let uniform = |counts: &[usize], cells: usize| {
+            let expected = samples / cells;
+            counts
+                .iter()
+                .all(|&count| count.abs_diff(expected) < expected / 8)
+        };
+        assert!(blocks.iter().all(|b| uniform(b, 4 * BINOMIAL_8_2)));
+        assert!(masks.iter().all(|m| uniform(m, 4 * 16)));
+    }
+
+    #[test]
+    fn test_doorkeeper_hash_independent() {
+        let mut rng = rand::thread_rng();
+        let samples = 1 << 16;
+        let same = (0..samples)
+            .filter(|_| {
+                let hash = rng.gen();
+                let (sketch, doorkeeper) = (&mut { hash }, &mut doorkeeper_hash(hash));
+                cache_line_index(sketch, 64) == cache_line_index(doorkeeper, 64)
+                    && block_indices_h(sketch) == block_indices_h(doorkeeper)
+            })
+            .count();
+        // Independent choices agree for one hash in 64 * 28.
+        assert!(same < 2 * samples / (64 * BINOMIAL_8_2));
+    }
+
+    #[test]
+    fn test_doorkeeper() {
+        let mut sketch = FrequencySketch::builder(64).doorkeeper(512).build();
+        assert!(sketch.has_doorkeeper());
+        assert_eq!(sketch.increment(&1), 1);
+        assert_eq!(sketch.frequency(&1), 1);
+        assert!(sketch.sketch.iter().all(|line| line.0 == [0; 8]));
+        assert_eq!(sketch.increment(&1), 2);
+        assert_eq!(sketch.increment(&1), 3);
+        assert_eq!(sketch.frequency(&1), 3);
+        for i in 0.. {
+            let size = sketch.size;
+            sketch.increment(&(i / 2));
+            if sketch.size < size {
+                break;
+            }
+        }
+        assert!(sketch.doorkeeper.as_ref().unwrap().is_empty());
+        assert!(!sketch
+            .doorkeeper
+            .as_ref()
+            .unwrap()
+            .contains_hash(make_hash(&sketch.hash_builder, &1)));
+    }
+
+    #[test]
+    fn test_prehashed() {
+        let mut sketch = FrequencySketch::with_capacity(64);
+        for i in 0..100u32 {
+            let hash = sketch.hasher().hash_one(i % 10);
+            assert_eq!(sketch.increment_hash(hash), sketch.frequency(&(i % 10)));
+        }
+        for i in 0..10u32 {
+            sketch.prefetch(&i);
+            assert_eq!(sketch.frequency_hash(sketch.hasher().hash_one(i)), 10);
+        }
+    }
+
+    #[test]
+    fn test_batched() {
+        let build = || seeded(256).doorkeeper(1024).build();
+        let (mut batched, mut batched_hash, mut reference) = (build(), build(), build());
+        let mut rng = rand::thread_rng();
+        let keys: Vec<u16> = (0..50_000).map(|_| rng.gen_range(0..4096)).collect();
+        let hashes: Vec<u64> = keys
+            .iter()
+            .map(|key| reference.hasher().hash_one(key))
+            .collect();
+        keys.iter().for_each(|key| {
+            reference.increment(key);
+        });
+        batched.increment_many(&keys);
+        batched_hash.increment_many_hash(&hashes);
+        assert_eq!(lines(&batched), lines(&reference));
+        assert_eq!(lines(&batched_hash), lines(&reference));
+        assert_eq!(batched.size, reference.size);
+        let mut frequencies = vec![0; keys.len()];
+        let mut frequencies_hash = vec![0; keys.len()];
+        batched.frequency_many(&keys, &mut frequencies);
+        batched.frequency_many_hash(&hashes, &mut frequencies_hash);
+        let expected: Vec<u8> = keys.iter().map(|key| reference.frequency(key)).collect();
+        assert_eq!(frequencies, expected);
+        assert_eq!(frequencies_hash, expected);
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let backends = Backend::ALL.iter().copied().filter(|b| b.is_supported());
+        for backend in backends {
+            let mut reference = seeded(1024).backend(Backend::Portable).build();
+            let mut sketch = seeded(1024).backend(backend).build();
+            assert_eq!(sketch.backend(), backend);
+            let mut rng = rand::thread_rng();
+            for _ in 0..1024 * 200 {
+                let key: u16 = rng.gen();
+                assert_eq!(sketch.frequency(&key), reference.frequency(&key));
+                assert_eq!(sketch.increment(&key), reference.increment(&key));
+                assert_eq!(sketch.size, reference.size);
+            }
+            assert_eq!(lines(&sketch), lines(&reference));
+        }
+    }
+
+    #[test]
+    fn test_conservative_update_accuracy() {
+        let build = |update_mode| seeded(64).update_mode(update_mode).build();
+        let mut standard = build(UpdateMode::Standard);
+        let mut conservative = build(UpdateMode::Conservative);
+        assert_eq!(conservative.update_mode(), UpdateMode::Conservative);
+        let mut counts = vec![0u8; 4096];
+        let mut rng = rand::thread_rng();
+        // Skewed towards low keys, and below the sample size so neither sketch halves.
+        for _ in 0..5000 {
+            let key = rng.gen_range(0..4096usize).min(rng.gen_range(0..4096));
+            counts[key] = counts[key].saturating_add(1);
+            standard.increment(&key);
+            conservative.increment(&key);
+        }
+        let (mut standard_error, mut conservative_error) = (0, 0);
+        for (key, &count) in counts.iter().enumerate() {
+            let count = count.min(15);
+            let (s, c) = (standard.frequency(&key), conservative.frequency(&key));
+            assert!(count <= c && c <= s);
+            standard_error += (s - count) as usize;
+            conservative_error += (c - count) as usize;
+        }
+        assert!(conservative_error * 3 < standard_error * 2);
+    }
+
+    #[test]
+    fn test_incremental_reset() {
+        let build = |incremental| {
+            let builder = seeded(64).doorkeeper(512);
+            match incremental {
+                true => builder.incremental_reset(1).build(),
+                false => builder.build(),
+            }
+        };
+        let (mut serial, mut incremental) = (build(false), build(true));
+        let mut rng = rand::thread_rng();
+        let mut resetting = 0;
+        for _ in 0..64 * 400 {
+            let key: u16 = rng.gen_range(0..2048);
+            assert_eq!(incremental.increment(&key), serial.increment(&key));
+            assert_eq!(incremental.frequency(&key), serial.frequency(&key));
+            resetting += incremental.is_resetting() as usize;
+            if !incremental.is_resetting() {
+                assert_eq!(incremental.size, serial.size);
+            }
+        }
+        assert!(resetting > 0);
+        serial.reset();
+        incremental.reset();
+        assert!(incremental.is_resetting());
+        assert!((0..2048u16).all(|key| incremental.frequency(&key) == serial.frequency(&key)));
+        let mut snapshot = Vec::new();
+        incremental.write_to(&mut snapshot).unwrap();
+        let hasher = incremental.hasher().clone();
+        let loaded = FrequencySketch::read_from(&snapshot[..], hasher, Global).unwrap();
+        incremental.finish_reset();
+        assert_eq!(lines(&incremental), lines(&serial));
+        assert_eq!(lines(&loaded), lines(&serial));
+        assert_eq!(incremental.size, serial.size);
+    }
+
+    #[test]
+    fn test_reset_parallel() {
+        let mut rng = rand::thread_rng();
+        for len in [1000, 2 * PARALLEL_RESET_MIN_LINES] {
+            let build = || seeded(len).aging(ManualAging).build();
+            let mut filled = build();
+            for _ in 0..len * 10 {
+                filled.increment(&rng.gen_range(0..len as u32 * 20));
+            }
+            for subtract_one in [false, true] {
+                let copy = || {
+                    let mut sketch = build();
+                    sketch.merge(&filled);
+                    if subtract_one {
+                        sketch.set_aging(SubtractOneDecay { factor: 80 });
+                    }
+                    sketch
+                };
+                let mut serial = copy();
+                serial.reset();
+                for chunk in [len / 4, len / 3, len] {
+                    let mut parallel = copy();
+                    parallel.reset_in_chunks(chunk);
+                    assert_eq!(lines(&parallel), lines(&serial));
+                    assert_eq!(parallel.size, serial.size);
+                }
+                let mut parallel = copy();
+                parallel.reset_parallel();
+                assert_eq!(lines(&parallel), lines(&serial));
+                assert_eq!(parallel.size, serial.size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sizing() {
+        let sketch = FrequencySketch::for_entries(1000);
+        assert_eq!((sketch.sketch.len(), sketch.sample_size()), (125, 10_000));
+        assert_eq!(sketch.memory_bytes(), 125 * 64);
+        let sketch = FrequencySketch::builder(64)
+            .doorkeeper(512)
+            .incremental_reset(1)
+            .build();
+        assert_eq!(sketch.memory_bytes(), 64 * 64 + 8 * 64 + 8);
+        let delta = (-8f64).exp();
+        for epsilon in [0.1, 0.01, 0.001] {
+            let sketch = FrequencySketch::with_error_bounds(epsilon, delta);
+            assert!(sketch.estimated_error() <= epsilon);
+            let lines = sketch.sketch.len() as f64;
+            assert!(sketch.estimated_error() * lines / (lines - 1.0) > epsilon);
+        }
+    }
+
+    #[test]
+    fn test_error_bounds() {
+        let (epsilon, delta) = (0.01, 0.01);
+        let mut sketch = FrequencySketch::with_error_bounds(epsilon, delta);
+        let mut counts = vec![0usize; 1 << 12];
+        let mut rng = rand::thread_rng();
+        for _ in 1..sketch.sample_size() {
+            let key = rng
+                .gen_range(0..counts.len())
+                .min(rng.gen_range(0..counts.len()));
+            counts[key] += 1;
+            sketch.increment(&key);
+        }
+        let bound = epsilon * sketch.sample_size() as f64;
+        let exceeded = counts
+            .iter()
+            .enumerate()
+            .filter(|&(key, &count)| {
+                (sketch.frequency(&key) as usize).saturating_sub(count) as f64 > bound
+            })
+            .count();
+        assert!(exceeded as f64 <= delta * counts.len() as f64);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut rng = rand::thread_rng();
+        for lines in [16, 4, 1, 3, 48, 96, 1000] {
+            let mut sketch = FrequencySketch::builder(48)
+                .aging(ManualAging)
+                .incremental_reset(1)
+                .build();
+            for _ in 0..5000 {
+                let key = rng.gen_range(0..1024u32).min(rng.gen_range(0..1024));
+                sketch.increment(&key);
+            }
+            for hot in 0..4u32 {
+                for _ in 0..15 {
+                    sketch.increment(&hot);
+                }
+            }
+            sketch.reset();
+            let before: Vec<_> = (0..1024u32).map(|key| sketch.frequency(&key)).collect();
+            sketch.resize(lines);
+            assert_eq!(sketch.sketch.len(), lines);
+            assert!(!sketch.is_resetting());
+            for (key, before) in (0..1024u32).zip(before) {
+                let after = sketch.frequency(&key);
+                assert!(after >= before);
+                // Growing by a whole factor copies every key's counters unchanged.
+                assert!(!lines.is_multiple_of(48) || after == before);
+            }
+            assert!((0..4u32).all(|hot| sketch.frequency(&hot) >= 7));
+        }
+    }
+
+    #[test]
+    fn test_clear_clone_eq() {
+        let build = || {
+            seeded(16)
+                .aging(SampleHalving { factor: 4 })
+                .incremental_reset(1)
+                .doorkeeper(64)
+                .build()
+        };
+        let mut sketch = build();
+        for i in 0..70u32 {
+            sketch.increment(&(i % 40));
+        }
+        assert!(sketch.is_resetting());
+        let mut clone = sketch.clone();
+        assert!(clone == sketch);
+        assert_eq!(clone.frequency(&0), sketch.frequency(&0));
+        clone.finish_reset();
+        assert!(clone == sketch);
+        clone.increment(&0);
+        assert!(clone != sketch);
+        sketch.clear();
+        assert!(sketch == build());
+        assert!(!sketch.is_resetting());
+        assert!((0..40u32).all(|i| sketch.frequency(&i) == 0));
+        let debug = format!("{:?}", sketch);
+        assert!(debug.starts_with("FrequencySketch { lines: 16, size: 0, sample_size: 64"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..16 {
+            let sketches: Vec<_> = (0..3)
+                .map(|_| {
+                    let mut sketch = seeded(16).doorkeeper(128).build();
+                    for _ in 0..rng.gen_range(0..2000) {
+                        sketch.increment(&rng.gen_range(0..256u32));
+                    }
+                    sketch
+                })
+                .collect();
+            let merge = |sketches: &[&FrequencySketch]| {
+                let mut merged = seeded(16).doorkeeper(128).build();
+                sketches.iter().for_each(|sketch| merged.merge(sketch));
+                merged
+            };
+            let (a, b, c) = (&sketches[0], &sketches[1], &sketches[2]);
+            let left = merge(&[&merge(&[a, b]), c]);
+            let right = merge(&[a, &merge(&[b, c])]);
+            assert_eq!(lines(&left), lines(&right));
+            assert_eq!(left.size, right.size);
+            for key in 0..256u32 {
+                let estimate = left.frequency(&key);
+                assert!(sketches.iter().all(|s| s.frequency(&key) <= estimate));
+            }
+        }
+    }
 }
@@ -0,0 +1,203 @@
+use super::CacheLine;
+
+const ONES: u64 = 0x1111_1111_1111_1111;
+const SEVENS: u64 = 0x7777_7777_7777_7777;
+const LOW_NIBBLES: u64 = 0x0F0F_0F0F_0F0F_0F0F;
+const LOW_BITS: u64 = 0x0101_0101_0101_0101;
+
+pub(super) fn mask_deinterleave(x_mask: u16, y_mask: u16) -> [u64; 2] {
+    fn deinterleave(mask: u16) -> u64 {
+        let mut register = mask as u64;
+        register |= register << 15;
+        register |= register << 30;
+        register & ONES
+    }
+    [deinterleave(x_mask), deinterleave(y_mask)]
+}
+
+fn mask_min(num: u64, mask: u64) -> u8 {
+    let mut register = num | !(mask * 0xF);
+    let mut min = 0xF;
+    for _ in 0..16 {
+        min = min.min(register as u8 & 0xF);
+        register >>= 4;
+    }
+    min
+}
+
+pub(super) const fn mask_saturating_increment(num: u64, mask: u64) -> u64 {
+    let register = num & (num >> 2);
+    num + (!(register & (register >> 1)) & mask)
+}
+
+pub(super) fn frequency((x, y): (u64, u64), (x_mask, y_mask): (u16, u16)) -> u8 {
+    let [mask_x, mask_y] = mask_deinterleave(x_mask, y_mask);
+    mask_min(x, mask_x).min(mask_min(y, mask_y))
+}
+
+pub(super) fn increment((x, y): (&mut u64, &mut u64), (x_mask, y_mask): (u16, u16)) -> (u8, bool) {
+    let [mask_x, mask_y] = mask_deinterleave(x_mask, y_mask);
+    let min = mask_min(*x, mask_x).min(mask_min(*y, mask_y));
+    *x = mask_saturating_increment(*x, mask_x);
+    *y = mask_saturating_increment(*y, mask_y);
+    let full_sat = min == 0xF;
+    (min + !full_sat as u8, full_sat)
+}
+
+/// Increments the counters of `mask` that are equal to `min`, which must be below 15.
+pub(super) const fn mask_increment_eq(num: u64, mask: u64, min: u8) -> u64 {
+    let mut register = num ^ (min as u64 * ONES);
+    register |= register >> 2;
+    register |= register >> 1;
+    num + (!register & mask)
+}
+
+pub(super) fn conservative_increment(
+    (x, y): (&mut u64, &mut u64),
+    (x_mask, y_mask): (u16, u16),
+) -> (u8, bool) {
+    let [mask_x, mask_y] = mask_deinterleave(x_mask, y_mask);
+    let min = mask_min(*x, mask_x).min(mask_min(*y, mask_y));
+    let full_sat = min == 0xF;
+    if !full_sat {
+        *x = mask_increment_eq(*x, mask_x, min);
+        *y = mask_increment_eq(*y, mask_y, min);
+    }
+    (min + !full_sat as u8, full_sat)
+}
+
+pub(super) fn contains((x, y): (u64, u64), (x_mask, y_mask): (u16, u16), offset: u32) -> bool {
+    let [mask_x, mask_y] = mask_deinterleave(x_mask, y_mask);
+    let (mask_x, mask_y) = (mask_x << offset, mask_y << offset);
+    x & mask_x == mask_x && y & mask_y == mask_y
+}
+
+/// Halves every counter of `block`, returning the halved block and the number of odd counters.
+pub(super) const fn reset_block(block: u64) -> (u64, u32) {
+    ((block >> 1) & SEVENS, (block & ONES).count_ones())
+}
+
+pub(super) fn reset(cache_line: &mut CacheLine) -> u8 {
+    let mut count = 0;
+    for block in cache_line.0.iter_mut() {
+        let (halved, odd) = reset_block(*block);
+        *block = halved;
+        count += odd;
+    }
+    count as _
+}
+
+/// Decrements every non-zero counter of `block`, returning the decremented block and the number
+/// of decremented counters.
+pub(super) const fn decrement_block(block: u64) -> (u64, u32) {
+    let mut nonzero = block | (block >> 2);
+    nonzero = (nonzero | (nonzero >> 1)) & ONES;
+    (block - nonzero, nonzero.count_ones())
+}
+
+pub(super) fn decrement(cache_line: &mut CacheLine) -> u8 {
+    let mut count = 0;
+    for block in cache_line.0.iter_mut() {
+        let (decremented, nonzero) = decrement_block(*block);
+        *block = decremented;
+        count += nonzero;
+    }
+    count as _
+}
+
+/// Adds the counters of `x` and `y` nibble by nibble, saturating at 15.
+pub(super) const fn saturating_add_block(x: u64, y: u64) -> u64 {
+    const fn add(x: u64, y: u64) -> u64 {
+        let sum = x + y;
+        (sum | (((sum >> 4) & LOW_BITS) * 0xF)) & LOW_NIBBLES
+    }
+    let lo = add(x & LOW_NIBBLES, y & LOW_NIBBLES);
+    let hi = add((x >> 4) & LOW_NIBBLES, (y >> 4) & LOW_NIBBLES);
+    lo | hi << 4
+}
+
+pub(super) fn merge(cache_line: &mut CacheLine, other: &CacheLine) {
+    for (block, &other) in cache_line.0.iter_mut().zip(other.0.iter()) {
+        *block = saturating_add_block(*block, other);
+    }
+}
+
+/// Keeps the larger counter of `x` and `y` nibble by nibble.
+pub(super) const fn max_block(x: u64, y: u64) -> u64 {
+    const fn max(x: u64, y: u64) -> u64 {
+        let ge = (((x | LOW_BITS << 4) - y) >> 4) & LOW_BITS;
+        let mask = ge * 0xF;
+        (x & mask) | (y & !mask & LOW_NIBBLES)
+    }
+    let lo = max(x & LOW_NIBBLES, y & LOW_NIBBLES);
+    let hi = max((x >> 4) & LOW_NIBBLES, (y >> 4) & LOW_NIBBLES);
+    lo | hi << 4
+}
+
+pub(super) fn max(cache_line: &mut CacheLine, other: &CacheLine) {
+    for (block, &other) in cache_line.0.iter_mut().zip(other.0.iter()) {
+        *block = max_block(*block, other);
+    }
+}
+
+pub(super) fn clear(cache_lines: &mut [CacheLine]) {
+    cache_lines.fill(CacheLine::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{
+        check_clear, check_conservative_increment, check_contains, check_decrement, check_merge,
+        check_reset, check_sat_inc_and_min,
+    };
+    use super::super::Backend;
+    use rand::Rng;
+
+    #[test]
+    fn test_sat_inc_and_min() {
+        check_sat_inc_and_min(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_conservative_increment() {
+        check_conservative_increment(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_contains() {
+        check_contains(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_reset() {
+        check_reset(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_decrement() {
+        check_decrement(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_merge() {
+        check_merge(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_clear() {
+        check_clear(Backend::Portable.kernels());
+    }
+
+    #[test]
+    fn test_max() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..(1 << 16) {
+            let (x, y): (u64, u64) = rng.gen();
+            let expected = (0..16).fold(0, |acc, i| {
+                let shift = i * 4;
+                acc | ((x >> shift) & 0xF).max((y >> shift) & 0xF) << shift
+            });
+            assert_eq!(super::max_block(x, y), expected);
+        }
+    }
+}
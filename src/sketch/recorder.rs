@@ -0,0 +1,196 @@
+use super::{make_hash, FrequencySketch};
+use ahash::RandomState;
+use allocator_api2::alloc::{Allocator, Global};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, TryLockError};
+
+/// What a [`SketchRecorder`] does with a sample when its buffer is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Tries to flush without waiting for the sketch lock and drops the sample if another thread
+    /// holds it. Recording never blocks, at the cost of undercounting under heavy contention,
+    /// which only slightly skews the frequency estimates.
+    #[default]
+    Drop,
+    /// Waits for the sketch lock and flushes, so no sample is ever lost.
+    Flush,
+}
+
+/// A per-thread handle that buffers key hashes and increments a shared [`FrequencySketch`] with
+/// them in batches, taking the lock once per flush instead of once per access.
+///
+/// The buffer is flushed when it fills up (see [`OverflowPolicy`]), on [`flush`](Self::flush),
+/// and when the recorder is dropped.
+pub struct SketchRecorder<S: BuildHasher = RandomState, A: Allocator = Global> {
+    sketch: Arc<Mutex<FrequencySketch<S, A>>>,
+    hash_builder: S,
+    buffer: Vec<u64>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: u64,
+}
+
+impl<S: BuildHasher + Clone, A: Allocator> SketchRecorder<S, A> {
+    /// Creates a recorder buffering up to `capacity` hashes and dropping samples on overflow.
+    pub fn new(sketch: Arc<Mutex<FrequencySketch<S, A>>>, capacity: usize) -> Self {
+        Self::with_policy(sketch, capacity, OverflowPolicy::Drop)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_policy(
+        sketch: Arc<Mutex<FrequencySketch<S, A>>>,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Self {
+        assert!(capacity > 0, "0 < capacity");
+        let hash_builder = sketch.lock().unwrap().hasher().clone();
+        Self {
+            sketch,
+            hash_builder,
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+            policy,
+            dropped: 0,
+        }
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> SketchRecorder<S, A> {
+    pub fn sketch(&self) -> &Arc<Mutex<FrequencySketch<S, A>>> {
+        &self.sketch
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Returns the number of buffered hashes not yet applied to the sketch.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the number of samples dropped under [`OverflowPolicy::Drop`].
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn record<Q: Hash + ?Sized>(&mut self, key: &Q) {
+        self.record_hash(make_hash(&self.hash_builder, key));
+    }
+
+    /// Like [`record`](Self::record), for a key already hashed with the sketch's hasher.
+    pub fn record_hash(&mut self, hash: u64) {
+        if self.buffer.len() == self.capacity {
+            let flushed = match self.policy {
+                OverflowPolicy::Drop => self.try_flush(),
+                OverflowPolicy::Flush => {
+                    self.flush();
+                    true
+                }
+            };
+            if !flushed {
+                self.dropped += 1;
+                return;
+            }
+        }
+        self.buffer.push(hash);
+    }
+
+    /// Applies the buffered hashes to the sketch, waiting for the lock.
+    pub fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            let mut sketch = self.sketch.lock().unwrap();
+            Self::drain(&mut self.buffer, &mut sketch);
+        }
+    }
+
+    /// Applies the buffered hashes to the sketch unless another thread holds the lock, returning
+    /// whether the buffer is now empty.
+    pub fn try_flush(&mut self) -> bool {
+        if self.buffer.is_empty() {
+            return true;
+        }
+        match self.sketch.try_lock() {
+            Ok(mut sketch) => Self::drain(&mut self.buffer, &mut sketch),
+            Err(TryLockError::WouldBlock) => return false,
+            Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+        }
+        true
+    }
+
+    fn drain(buffer: &mut Vec<u64>, sketch: &mut FrequencySketch<S, A>) {
+        sketch.increment_many_hash(buffer);
+        buffer.clear();
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> Drop for SketchRecorder<S, A> {
+    fn drop(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        if let Ok(mut sketch) = self.sketch.lock() {
+            Self::drain(&mut self.buffer, &mut sketch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::seeded;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_flush_matches_direct_increments() {
+        let mut reference = seeded(64).build();
+        let sketch = Arc::new(Mutex::new(seeded(64).build()));
+        let mut recorder = SketchRecorder::with_policy(sketch.clone(), 16, OverflowPolicy::Flush);
+        for i in 0..10_000u32 {
+            recorder.record(&(i % 100));
+            reference.increment(&(i % 100));
+        }
+        drop(recorder);
+        let sketch = sketch.lock().unwrap();
+        assert!((0..100u32).all(|i| sketch.frequency(&i) == reference.frequency(&i)));
+    }
+
+    #[test]
+    fn test_drop_when_contended() {
+        let sketch = Arc::new(Mutex::new(FrequencySketch::with_capacity(64)));
+        let mut recorder = SketchRecorder::new(sketch.clone(), 4);
+        let guard = sketch.lock().unwrap();
+        for _ in 0..10 {
+            recorder.record("key");
+        }
+        assert_eq!((recorder.len(), recorder.dropped()), (4, 6));
+        drop(guard);
+        recorder.record("key");
+        assert_eq!((recorder.len(), recorder.dropped()), (1, 6));
+        assert_eq!(sketch.lock().unwrap().frequency("key"), 4);
+    }
+
+    #[test]
+    fn test_threads() {
+        let sketch = Arc::new(Mutex::new(FrequencySketch::with_capacity(64)));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                let sketch = sketch.clone();
+                s.spawn(move || {
+                    let mut recorder =
+                        SketchRecorder::with_policy(sketch, 8, OverflowPolicy::Flush);
+                    for _ in 0..3 {
+                        recorder.record("hot");
+                    }
+                });
+            }
+        });
+        assert_eq!(sketch.lock().unwrap().frequency("hot"), 12);
+    }
+}
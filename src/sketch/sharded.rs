@@ -0,0 +1,210 @@
+use super::{make_hash, reduce, Backend, FrequencySketch, SketchError};
+use ahash::RandomState;
+use allocator_api2::alloc::{Allocator, Global};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, MutexGuard};
+
+#[repr(C, align(64))]
+struct Shard<S: BuildHasher, A: Allocator>(Mutex<FrequencySketch<S, A>>);
+
+/// A [`FrequencySketch`] split into independently locked shards.
+///
+/// A key's shard is picked from the high bits of its hash mixed with a multiplicative constant,
+/// so the shard never correlates with the line, block or counter bits used inside it. Every shard
+/// counts its own samples and halves its counters on its own, so writers to different shards never
+/// wait on each other, not even during a reset.
+pub struct ShardedFrequencySketch<S: BuildHasher = RandomState, A: Allocator = Global> {
+    shards: Box<[Shard<S, A>]>,
+    hash_builder: S,
+}
+
+fn shard_index(hash: u64, shards: usize) -> usize {
+    reduce(&mut hash.wrapping_mul(0xD6E8_FEB8_6659_FD93), shards as u64) as usize
+}
+
+impl ShardedFrequencySketch<RandomState, Global> {
+    pub fn with_capacity(sketch_size: usize, shards: usize) -> Self {
+        Self::with_capacity_and_hasher_in(sketch_size, shards, RandomState::new(), Global)
+    }
+
+    /// Like [`with_capacity`](Self::with_capacity), returning an error instead of panicking or
+    /// aborting.
+    pub fn try_with_capacity(sketch_size: usize, shards: usize) -> Result<Self, SketchError> {
+        Self::try_with_capacity_and_hasher_in(sketch_size, shards, RandomState::new(), Global)
+    }
+}
+
+impl<S: BuildHasher + Clone, A: Allocator + Clone> ShardedFrequencySketch<S, A> {
+    /// Creates a sketch of `sketch_size` cache lines in total, spread over `shards` shards that
+    /// differ by at most one line.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero or greater than `sketch_size`, or if a shard would be larger
+    /// than 2^35 cache lines, and aborts if an allocation fails. See
+    /// [`try_with_capacity_and_hasher_in`](Self::try_with_capacity_and_hasher_in).
+    pub fn with_capacity_and_hasher_in(
+        sketch_size: usize,
+        shards: usize,
+        hasher: S,
+        alloc: A,
+    ) -> Self {
+        Self::try_with_capacity_and_hasher_in(sketch_size, shards, hasher, alloc)
+            .unwrap_or_else(|err| err.raise())
+    }
+
+    /// Like [`with_capacity_and_hasher_in`](Self::with_capacity_and_hasher_in), failing with
+    /// [`SketchError::InvalidShards`] for an invalid shard count and with the errors of
+    /// [`FrequencySketch::try_with_capacity_and_hasher_in`] for the shards.
+    pub fn try_with_capacity_and_hasher_in(
+        sketch_size: usize,
+        shards: usize,
+        hasher: S,
+        alloc: A,
+    ) -> Result<Self, SketchError> {
+        if shards == 0 || shards > sketch_size {
+            return Err(SketchError::InvalidShards(shards));
+        }
+        let (shard_size, remainder) = (sketch_size / shards, sketch_size % shards);
+        let shards = (0..shards)
+            .map(|index| {
+                let sketch = FrequencySketch::try_with_capacity_and_hasher_in(
+                    shard_size + (index < remainder) as usize,
+                    hasher.clone(),
+                    alloc.clone(),
+                )?;
+                Ok(Shard(Mutex::new(sketch)))
+            })
+            .collect::<Result<_, SketchError>>()?;
+        Ok(Self {
+            shards,
+            hash_builder: hasher,
+        })
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> ShardedFrequencySketch<S, A> {
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.lock(0).backend()
+    }
+
+    /// Forces every shard to use `backend` instead of the one detected at construction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the running CPU does not support `backend`.
+    pub fn force_backend(&mut self, backend: Backend) {
+        for shard in self.shards.iter_mut() {
+            shard.0.get_mut().unwrap().force_backend(backend);
+        }
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    fn lock(&self, index: usize) -> MutexGuard<'_, FrequencySketch<S, A>> {
+        self.shards[index].0.lock().unwrap()
+    }
+
+    fn shard(&self, hash: u64) -> MutexGuard<'_, FrequencySketch<S, A>> {
+        self.lock(shard_index(hash, self.shards.len()))
+    }
+
+    pub fn frequency<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.frequency_hash(make_hash(&self.hash_builder, key))
+    }
+
+    /// Like [`frequency`](Self::frequency), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn frequency_hash(&self, hash: u64) -> u8 {
+        self.shard(hash).frequency_hash(hash)
+    }
+
+    pub fn increment<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.increment_hash(make_hash(&self.hash_builder, key))
+    }
+
+    /// Like [`increment`](Self::increment), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn increment_hash(&self, hash: u64) -> u8 {
+        self.shard(hash).increment_hash(hash)
+    }
+
+    /// Halves the counters of every shard, one shard at a time.
+    pub fn reset(&self) {
+        for index in 0..self.shards.len() {
+            self.lock(index).reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{seeded, Failing};
+    use super::*;
+    use rand::Rng;
+    use std::thread;
+
+    #[test]
+    fn test_try_with_capacity() {
+        let shards = |lines, shards| {
+            ShardedFrequencySketch::try_with_capacity(lines, shards)
+                .err()
+                .unwrap()
+        };
+        assert_eq!(shards(16, 0), SketchError::InvalidShards(0));
+        assert_eq!(shards(16, 17), SketchError::InvalidShards(17));
+        let hasher = RandomState::new();
+        let sketch =
+            ShardedFrequencySketch::try_with_capacity_and_hasher_in(16, 4, hasher, Failing);
+        assert_eq!(
+            sketch.err(),
+            Some(SketchError::AllocationFailed { bytes: 4 * 64 })
+        );
+        let sketch = ShardedFrequencySketch::try_with_capacity(10, 3).unwrap();
+        let sizes: Vec<_> = (0..3).map(|i| sketch.lock(i).sketch.len()).collect();
+        assert_eq!(sizes, [4, 3, 3]);
+    }
+
+    #[test]
+    fn test_single_shard_matches_frequency_sketch() {
+        let mut reference = seeded(64).build();
+        let hasher = reference.hasher().clone();
+        let sharded = ShardedFrequencySketch::with_capacity_and_hasher_in(64, 1, hasher, Global);
+        let mut rng = rand::thread_rng();
+        for _ in 0..64 * 200 {
+            let key: u16 = rng.gen_range(0..1024);
+            assert_eq!(sharded.increment(&key), reference.increment(&key));
+            assert_eq!(sharded.frequency(&key), reference.frequency(&key));
+        }
+    }
+
+    #[test]
+    fn test_sharded_increments() {
+        const THREADS: u32 = 4;
+        let sketch = ShardedFrequencySketch::with_capacity(1024, 8);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let sketch = &sketch;
+                s.spawn(move || {
+                    for i in 0..100_000u32 {
+                        sketch.increment(&(i % 64 + t * 64));
+                        sketch.increment(&u32::MAX);
+                    }
+                });
+            }
+        });
+        assert!(sketch.frequency(&u32::MAX) >= 7);
+        let mut used = [false; 8];
+        for i in 0..1024u64 {
+            used[shard_index(sketch.hasher().hash_one(i), 8)] = true;
+        }
+        assert!(used.iter().all(|&used| used));
+        let shards = (u32::MAX as usize) << 2;
+        assert!((0..64u64).any(|i| shard_index(i << 58, shards) > u32::MAX as usize));
+        sketch.reset();
+    }
+}
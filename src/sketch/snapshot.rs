@@ -0,0 +1,378 @@
+//! Snapshot format, all integers little-endian:
+//!
+//! | offset | field                                   |
+//! |--------|-----------------------------------------|
+//! | 0      | magic `b"TINYLFU\0"`                    |
+//! | 8      | format version (`u32`)                  |
+//! | 12     | cache line size in bytes (`u32`)        |
+//! | 16     | cache lines (`u64`)                     |
+//! | 24     | `size` (`u64`)                          |
+//! | 32     | `sample_size` (`u64`)                   |
+//! | 40     | doorkeeper cache lines, 0 if none       |
+//! | 48     | doorkeeper length                       |
+//! | 56     | hasher fingerprint (`u64`)              |
+//! | 64     | checksum of every other field and word  |
+//! | 72     | zero padding up to 128                  |
+//! | 128    | sketch words, then doorkeeper words     |
+//!
+//! The header spans two cache lines so that the words of a snapshot loaded at an aligned address
+//! are themselves cache line aligned.
+
+use super::{
+    max_lines, BlockedBloomFilter, CacheLine, FrequencySketch, FrequencySketchBuilder, SketchError,
+};
+use allocator_api2::alloc::Allocator;
+use std::convert::TryInto;
+use std::hash::BuildHasher;
+use std::io::{self, Read, Write};
+use std::mem;
+
+pub(super) const MAGIC: [u8; 8] = *b"TINYLFU\0";
+pub(super) const VERSION: u32 = 1;
+pub(super) const HEADER_LEN: usize = 128;
+const FINGERPRINT_PROBE: u64 = 0x7469_6E79_6C66_7521;
+/// Cache lines buffered per write while aging a sketch with an unfinished reset.
+const WRITE_BATCH: usize = 64;
+
+pub(super) struct Header {
+    pub(super) lines: u64,
+    pub(super) size: u64,
+    pub(super) sample_size: u64,
+    pub(super) doorkeeper_lines: u64,
+    pub(super) doorkeeper_len: u64,
+    pub(super) fingerprint: u64,
+    pub(super) checksum: u64,
+}
+
+pub(super) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(super) fn fingerprint<S: BuildHasher>(hasher: &S) -> u64 {
+    hasher.hash_one(FINGERPRINT_PROBE)
+}
+
+/// Maps a failure to create the sketch a snapshot is loaded into to an [`io::Error`].
+fn sketch_error(err: SketchError) -> io::Error {
+    let kind = match err {
+        SketchError::AllocationFailed { .. } => io::ErrorKind::OutOfMemory,
+        _ => io::ErrorKind::InvalidInput,
+    };
+    io::Error::new(kind, err)
+}
+
+fn checksum(seed: u64, words: &[u64]) -> u64 {
+    words.iter().fold(seed, |hash, &word| {
+        (hash ^ word)
+            .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            .rotate_left(29)
+    })
+}
+
+pub(super) fn lines_checksum(seed: u64, lines: &[CacheLine]) -> u64 {
+    checksum(seed, bytemuck::cast_slice(lines))
+}
+
+impl Header {
+    fn fields(&self) -> [u64; 6] {
+        [
+            self.lines,
+            self.size,
+            self.sample_size,
+            self.doorkeeper_lines,
+            self.doorkeeper_len,
+            self.fingerprint,
+        ]
+    }
+
+    pub(super) fn checksum_seed(&self) -> u64 {
+        checksum(VERSION as u64, &self.fields())
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[12..16].copy_from_slice(&(mem::size_of::<CacheLine>() as u32).to_le_bytes());
+        let words = IntoIterator::into_iter(self.fields()).chain([self.checksum]);
+        for (chunk, word) in bytes[16..72].chunks_exact_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parses a header, checking everything that doesn't depend on the payload or the hasher.
+    pub(super) fn from_bytes(bytes: &[u8; HEADER_LEN]) -> io::Result<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        if bytes[..8] != MAGIC {
+            return Err(invalid_data("not a sketch snapshot"));
+        }
+        if u32_at(8) != VERSION {
+            return Err(invalid_data("unsupported snapshot version"));
+        }
+        let header = Header {
+            lines: u64_at(16),
+            size: u64_at(24),
+            sample_size: u64_at(32),
+            doorkeeper_lines: u64_at(40),
+            doorkeeper_len: u64_at(48),
+            fingerprint: u64_at(56),
+            checksum: u64_at(64),
+        };
+        let valid_lines = |lines| lines <= max_lines() as u64;
+        if u32_at(12) as usize != mem::size_of::<CacheLine>()
+            || header.lines == 0
+            || !valid_lines(header.lines)
+            || !valid_lines(header.doorkeeper_lines)
+        {
+            return Err(invalid_data("snapshot layout mismatch"));
+        }
+        Ok(header)
+    }
+}
+
+fn write_lines<W: Write>(writer: &mut W, lines: &[CacheLine]) -> io::Result<()> {
+    if cfg!(target_endian = "little") {
+        return writer.write_all(bytemuck::cast_slice(lines));
+    }
+    for line in lines {
+        for word in line.0 {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes every line of `lines`, buffering [`WRITE_BATCH`] at a time.
+fn write_aged_lines<W: Write>(
+    writer: &mut W,
+    lines: impl Iterator<Item = CacheLine>,
+) -> io::Result<()> {
+    let mut batch = [CacheLine::default(); WRITE_BATCH];
+    let mut len = 0;
+    for line in lines {
+        batch[len] = line;
+        len += 1;
+        if len == WRITE_BATCH {
+            write_lines(writer, &batch)?;
+            len = 0;
+        }
+    }
+    write_lines(writer, &batch[..len])
+}
+
+fn read_lines<R: Read>(reader: &mut R, lines: &mut [CacheLine]) -> io::Result<()> {
+    reader.read_exact(bytemuck::cast_slice_mut(lines))?;
+    for word in bytemuck::cast_slice_mut::<_, u64>(lines) {
+        *word = u64::from_le(*word);
+    }
+    Ok(())
+}
+
+impl<S: BuildHasher, A: Allocator> FrequencySketch<S, A> {
+    /// Writes a snapshot of the counters, the sample count and the doorkeeper to `writer`.
+    ///
+    /// The snapshot can only be loaded with [`read_from`](Self::read_from) by a sketch using an
+    /// identically seeded hasher.
+    ///
+    /// The counters are written as they are once any unfinished reset finishes, each line aged as
+    /// it is written rather than finishing the reset or copying the sketch.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let count = self.aged_lines().map(|(_, count)| count).sum();
+        let doorkeeper = self.doorkeeper.as_ref();
+        let doorkeeper_lines = doorkeeper.map_or(&[][..], |d| &d.lines[..]);
+        let mut header = Header {
+            lines: self.sketch.len() as u64,
+            size: self.aged_size(count) as u64,
+            sample_size: self.sample_size as u64,
+            doorkeeper_lines: doorkeeper_lines.len() as u64,
+            doorkeeper_len: doorkeeper.map_or(0, |d| d.len) as u64,
+            fingerprint: fingerprint(&self.hash_builder),
+            checksum: 0,
+        };
+        let checksum = self
+            .aged_lines()
+            .fold(header.checksum_seed(), |checksum, (line, _)| {
+                lines_checksum(checksum, &[line])
+            });
+        header.checksum = lines_checksum(checksum, doorkeeper_lines);
+        writer.write_all(&header.to_bytes())?;
+        write_aged_lines(&mut writer, self.aged_lines().map(|(line, _)| line))?;
+        write_lines(&mut writer, doorkeeper_lines)
+    }
+}
+
+impl<S: BuildHasher, A: Allocator> FrequencySketch<S, A> {
+    /// Reads the counters, doorkeeper bits and sample counts of a snapshot into this sketch,
+    /// which must have as many cache lines and doorkeeper lines.
+    fn read_payload<R: Read>(&mut self, header: &Header, mut reader: R) -> io::Result<()> {
+        if header.fingerprint != fingerprint(&self.hash_builder) {
+            return Err(invalid_data("snapshot taken with a different hasher"));
+        }
+        let doorkeeper_lines = self.doorkeeper.as_ref().map_or(0, |d| d.lines.len());
+        if header.lines != self.sketch.len() as u64
+            || header.doorkeeper_lines != doorkeeper_lines as u64
+        {
+            return Err(invalid_data("snapshot size mismatch"));
+        }
+        read_lines(&mut reader, &mut self.sketch)?;
+        let mut checksum = lines_checksum(header.checksum_seed(), &self.sketch);
+        if let Some(doorkeeper) = &mut self.doorkeeper {
+            read_lines(&mut reader, &mut doorkeeper.lines)?;
+            checksum = lines_checksum(checksum, &doorkeeper.lines);
+            doorkeeper.len = header.doorkeeper_len as usize;
+        }
+        if checksum != header.checksum {
+            return Err(invalid_data("snapshot checksum mismatch"));
+        }
+        self.size = header.size as usize;
+        self.sample_size = header.sample_size as usize;
+        Ok(())
+    }
+}
+
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Header> {
+    let mut bytes = [0; HEADER_LEN];
+    reader.read_exact(&mut bytes)?;
+    Header::from_bytes(&bytes)
+}
+
+impl<S: BuildHasher, A: Allocator + Clone> FrequencySketch<S, A> {
+    /// Loads a snapshot written by [`write_to`](Self::write_to).
+    ///
+    /// A snapshot only holds the counters, the doorkeeper and the sample count and size. The
+    /// sketch is loaded with [`UpdateMode::Standard`](super::UpdateMode::Standard), the default
+    /// [`SampleHalving`](super::SampleHalving), which recomputes the sample size at the next
+    /// reset, and no incremental reset, whatever the sketch written had. Use
+    /// [`FrequencySketchBuilder::read_from`] to load it with the same configuration.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the snapshot is corrupt, was written by an
+    /// incompatible version or layout, or was taken with a differently seeded hasher.
+    pub fn read_from<R: Read>(mut reader: R, hasher: S, alloc: A) -> io::Result<Self> {
+        let header = read_header(&mut reader)?;
+        if header.fingerprint != fingerprint(&hasher) {
+            return Err(invalid_data("snapshot taken with a different hasher"));
+        }
+        let lines = header.lines as usize;
+        let mut sketch = Self::try_with_capacity_and_hasher_in(lines, hasher, alloc.clone())
+            .map_err(sketch_error)?;
+        if header.doorkeeper_lines > 0 {
+            let lines = header.doorkeeper_lines as usize;
+            let doorkeeper = BlockedBloomFilter::try_with_capacity_and_hasher_in(lines, (), alloc)
+                .map_err(sketch_error)?;
+            sketch.doorkeeper = Some(doorkeeper);
+        }
+        sketch.read_payload(&header, reader)?;
+        Ok(sketch)
+    }
+}
+
+impl<S: BuildHasher, A: Allocator + Clone> FrequencySketchBuilder<S, A> {
+    /// Builds the sketch and loads a snapshot written by [`FrequencySketch::write_to`] into it.
+    ///
+    /// The counters, doorkeeper bits and sample count and size come from the snapshot, and the
+    /// update mode, aging policy, incremental reset and backend from the builder. The sample size
+    /// follows the aging policy again from the next reset.
+    ///
+    /// Fails like [`FrequencySketch::read_from`], with [`io::ErrorKind::InvalidData`] if the
+    /// sketch and doorkeeper sizes differ from the snapshot's, and with the error of
+    /// [`try_build`](Self::try_build) if the sketch can't be built.
+    pub fn read_from<R: Read>(self, mut reader: R) -> io::Result<FrequencySketch<S, A>> {
+        let header = read_header(&mut reader)?;
+        let mut sketch = self.try_build().map_err(sketch_error)?;
+        sketch.read_payload(&header, reader)?;
+        Ok(sketch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{lines, seeded};
+    use super::super::{SampleHalving, UpdateMode};
+    use super::*;
+    use ahash::RandomState;
+    use allocator_api2::alloc::Global;
+
+    fn snapshot(doorkeeper: bool) -> (FrequencySketch, Vec<u8>) {
+        let builder = seeded(64);
+        let builder = if doorkeeper {
+            builder.doorkeeper(512)
+        } else {
+            builder
+        };
+        let mut sketch = builder.build();
+        for i in 0..4096u32 {
+            sketch.increment(&(i % 300));
+        }
+        let mut bytes = Vec::new();
+        sketch.write_to(&mut bytes).unwrap();
+        (sketch, bytes)
+    }
+
+    fn load(bytes: &[u8], seed: u64) -> io::Result<FrequencySketch> {
+        let hasher = RandomState::with_seeds(seed, 2, 3, 4);
+        FrequencySketch::read_from(bytes, hasher, Global)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for doorkeeper in [false, true] {
+            let (sketch, bytes) = snapshot(doorkeeper);
+            assert_eq!(
+                bytes.len(),
+                HEADER_LEN + 64 * (64 + doorkeeper as usize * 8)
+            );
+            let loaded = load(&bytes, 1).unwrap();
+            assert_eq!(loaded.has_doorkeeper(), doorkeeper);
+            assert_eq!(
+                (loaded.size, loaded.sample_size),
+                (sketch.size, sketch.sample_size)
+            );
+            assert_eq!(lines(&loaded), lines(&sketch));
+            assert!((0..600u32).all(|i| loaded.frequency(&i) == sketch.frequency(&i)));
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let (_, bytes) = snapshot(true);
+        let kind = |bytes: &[u8], seed| load(bytes, seed).err().unwrap().kind();
+        assert_eq!(kind(&bytes, 5), io::ErrorKind::InvalidData);
+        assert_eq!(
+            kind(&bytes[..bytes.len() - 1], 1),
+            io::ErrorKind::UnexpectedEof
+        );
+        for at in [0, 8, 12, 24, 32, 56, HEADER_LEN + 100, bytes.len() - 1] {
+            let mut corrupt = bytes.clone();
+            corrupt[at] ^= 1;
+            assert_eq!(kind(&corrupt, 1), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_builder_read_from() {
+        let builder = || {
+            seeded(64)
+                .doorkeeper(512)
+                .update_mode(UpdateMode::Conservative)
+                .aging(SampleHalving { factor: 4 })
+                .incremental_reset(1)
+        };
+        let mut sketch = builder().build();
+        for i in 0..200u32 {
+            sketch.increment(&(i % 30));
+        }
+        let mut bytes = Vec::new();
+        sketch.write_to(&mut bytes).unwrap();
+        let loaded = builder().read_from(&bytes[..]).unwrap();
+        assert!(loaded == sketch);
+        assert_eq!(loaded.update_mode(), UpdateMode::Conservative);
+        assert!(loaded.incremental.is_some());
+        assert_eq!(loaded.aging.sample_size(64), 256);
+        for builder in [seeded(32), seeded(64)] {
+            let err = builder.read_from(&bytes[..]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use super::snapshot::{fingerprint, invalid_data, lines_checksum, Header, HEADER_LEN};
+use super::{cache_line_index, doorkeeper_hash, make_hash, Backend, CacheLine, Kernels};
+use ahash::RandomState;
+use std::convert::{TryFrom, TryInto};
+use std::hash::{BuildHasher, Hash};
+use std::io;
+use std::mem;
+
+/// A read-only [`FrequencySketch`](super::FrequencySketch) borrowing its counters from a
+/// snapshot in memory, such as a memory-mapped file written by
+/// [`write_to`](super::FrequencySketch::write_to).
+///
+/// Estimates are identical to those of the sketch the snapshot was taken from.
+pub struct FrequencySketchRef<'a, S = RandomState> {
+    sketch: &'a [CacheLine],
+    doorkeeper: Option<&'a [CacheLine]>,
+    size: usize,
+    sample_size: usize,
+    hash_builder: S,
+    kernels: Kernels,
+}
+
+impl<'a, S: BuildHasher> FrequencySketchRef<'a, S> {
+    /// Interprets `bytes` as a snapshot without copying the counters.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if `bytes` doesn't start at a cache line
+    /// boundary, isn't exactly one snapshot long, or fails any check of
+    /// [`read_from`](super::FrequencySketch::read_from). Always fails on big-endian targets,
+    /// where the little-endian words can't be used in place.
+    pub fn new(bytes: &'a [u8], hasher: S) -> io::Result<Self> {
+        if cfg!(target_endian = "big") {
+            return Err(invalid_data(
+                "zero-copy views require a little-endian target",
+            ));
+        }
+        if !(bytes.as_ptr() as usize).is_multiple_of(mem::align_of::<CacheLine>()) {
+            return Err(invalid_data("snapshot is not cache line aligned"));
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err(invalid_data("snapshot is truncated"));
+        }
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        let header = Header::from_bytes(header.try_into().unwrap())?;
+        let payload_len = usize::try_from(header.lines + header.doorkeeper_lines)
+            .ok()
+            .and_then(|lines| lines.checked_mul(mem::size_of::<CacheLine>()));
+        if payload_len != Some(payload.len()) {
+            return Err(invalid_data("snapshot length mismatch"));
+        }
+        if header.fingerprint != fingerprint(&hasher) {
+            return Err(invalid_data("snapshot taken with a different hasher"));
+        }
+        let payload: &[CacheLine] = bytemuck::cast_slice(payload);
+        let (sketch, doorkeeper) = payload.split_at(header.lines as usize);
+        if lines_checksum(header.checksum_seed(), payload) != header.checksum {
+            return Err(invalid_data("snapshot checksum mismatch"));
+        }
+        Ok(Self {
+            sketch,
+            doorkeeper: Some(doorkeeper).filter(|d| !d.is_empty()),
+            size: header.size as usize,
+            sample_size: header.sample_size as usize,
+            hash_builder: hasher,
+            kernels: Backend::detect().kernels(),
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.kernels.backend()
+    }
+
+    /// Forces the view to use `backend` instead of the one detected at construction.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the running CPU does not support `backend`.
+    pub fn force_backend(&mut self, backend: Backend) {
+        self.kernels = backend.kernels();
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn has_doorkeeper(&self) -> bool {
+        self.doorkeeper.is_some()
+    }
+
+    /// Returns the number of samples recorded since the last reset when the snapshot was taken.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn sample_size(&self) -> usize {
+        self.sample_size
+    }
+
+    pub fn frequency<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.frequency_hash(make_hash(&self.hash_builder, key))
+    }
+
+    /// Like [`frequency`](Self::frequency), for a key already hashed with [`hasher`](Self::hasher).
+    pub fn frequency_hash(&self, hash: u64) -> u8 {
+        let admitted = self.doorkeeper.is_some_and(|doorkeeper| {
+            let hash = &mut doorkeeper_hash(hash);
+            let index = cache_line_index(hash, doorkeeper.len());
+            doorkeeper[index].contains(hash, &self.kernels)
+        });
+        let hash = &mut { hash };
+        let index = cache_line_index(hash, self.sketch.len());
+        self.sketch[index].frequency(hash, &self.kernels) + admitted as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::seeded;
+    use super::super::{max_lines, FrequencySketch};
+    use super::*;
+
+    fn aligned_snapshot(sketch: &FrequencySketch) -> Vec<CacheLine> {
+        let mut bytes = Vec::new();
+        sketch.write_to(&mut bytes).unwrap();
+        let mut lines = vec![CacheLine::default(); bytes.len() / mem::size_of::<CacheLine>()];
+        bytemuck::cast_slice_mut(&mut lines).copy_from_slice(&bytes);
+        lines
+    }
+
+    #[test]
+    fn test_matches_frequency_sketch() {
+        for doorkeeper in [None, Some(512)] {
+            let builder = seeded(64);
+            let mut sketch = match doorkeeper {
+                Some(entries) => builder.doorkeeper(entries).build(),
+                None => builder.build(),
+            };
+            for i in 0..4096u32 {
+                sketch.increment(&(i % 300));
+            }
+            let lines = aligned_snapshot(&sketch);
+            let hasher = sketch.hasher().clone();
+            let view = FrequencySketchRef::new(bytemuck::cast_slice(&lines), hasher).unwrap();
+            assert_eq!(view.has_doorkeeper(), doorkeeper.is_some());
+            assert!((0..600u32).all(|i| view.frequency(&i) == sketch.frequency(&i)));
+        }
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let sketch = seeded(8).build();
+        let hasher = sketch.hasher().clone();
+        let lines = aligned_snapshot(&sketch);
+        let bytes: &[u8] = bytemuck::cast_slice(&lines);
+        assert!(FrequencySketchRef::new(bytes, hasher.clone()).is_ok());
+        assert!(FrequencySketchRef::new(&bytes[1..], hasher.clone()).is_err());
+        assert!(FrequencySketchRef::new(&bytes[..bytes.len() - 64], hasher.clone()).is_err());
+        assert!(FrequencySketchRef::new(&bytes[..64], hasher.clone()).is_err());
+        let mut huge = lines.clone();
+        let lines_field = &mut bytemuck::cast_slice_mut::<_, u8>(&mut huge)[16..24];
+        lines_field.copy_from_slice(&(max_lines() as u64).to_le_bytes());
+        assert!(FrequencySketchRef::new(bytemuck::cast_slice(&huge), hasher.clone()).is_err());
+        let other = RandomState::with_seeds(5, 6, 7, 8);
+        assert!(FrequencySketchRef::new(bytes, other).is_err());
+    }
+}